@@ -0,0 +1,105 @@
+use crate::logger::ConsoleLogger;
+use crate::packet_handler::definitions;
+use crate::packet_handler::direction::Direction;
+use crate::packet_handler::header_translation::HeaderTranslator;
+use crate::packet_handler::message_registry::MessageRegistry;
+use crate::packet_handler::release_diff::ReleaseDiff;
+use crate::settings::Settings;
+
+const USAGE: &str = "usage: hablog [diff <old> <new> | translate <old> <new> <file> [in|out]]\n\
+                     <old> and <new> are release names or definitions files";
+
+// One-off commands that don't start the proxy. Returns the exit code.
+pub async fn run(args: &[String], settings: &Settings) -> i32 {
+    match args
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .as_slice()
+    {
+        ["diff", old, new] => diff(settings, old, new).await,
+        ["translate", old, new, path] => translate(settings, old, new, path, Direction::Out).await,
+        ["translate", old, new, path, direction] => match Direction::parse(direction) {
+            Some(direction) => translate(settings, old, new, path, direction).await,
+            None => {
+                eprintln!("{}", USAGE);
+                2
+            }
+        },
+        _ => {
+            eprintln!("{}", USAGE);
+            2
+        }
+    }
+}
+
+async fn load_both(
+    settings: &Settings,
+    old: &str,
+    new: &str,
+) -> Option<(MessageRegistry, MessageRegistry)> {
+    match (
+        definitions::load_release(settings, old).await,
+        definitions::load_release(settings, new).await,
+    ) {
+        (Ok(old_registry), Ok(new_registry)) => Some((old_registry, new_registry)),
+        (Err(e), _) | (_, Err(e)) => {
+            ConsoleLogger::warning(format!("Could not load definitions: {}", e));
+            None
+        }
+    }
+}
+
+// Rewrites a file of saved packets, one expression per line, and prints it with the new release's
+// header ids. `direction` is used for lines that don't say, escaped frames and {h:..}. Lines that
+// can't be moved are printed unchanged and reported on stderr, so nothing gets lost.
+async fn translate(
+    settings: &Settings,
+    old: &str,
+    new: &str,
+    path: &str,
+    direction: Direction,
+) -> i32 {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) => {
+            ConsoleLogger::warning(format!("Could not read {}: {}", path, e));
+            return 1;
+        }
+    };
+    let Some((old_registry, new_registry)) = load_both(settings, old, new).await else {
+        return 1;
+    };
+    let translator = HeaderTranslator::new(&old_registry, &new_registry);
+
+    let mut failed = 0;
+    for (number, line) in contents.lines().enumerate() {
+        if line.trim().is_empty() || line.trim_start().starts_with('#') {
+            println!("{}", line);
+            continue;
+        }
+        match translator.translate_line(line, direction) {
+            Ok(translated) => println!("{}", translated),
+            Err(e) => {
+                eprintln!("{}:{}: {}", path, number + 1, e);
+                println!("{}", line);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        1
+    } else {
+        0
+    }
+}
+
+async fn diff(settings: &Settings, old: &str, new: &str) -> i32 {
+    let Some((old_registry, new_registry)) = load_both(settings, old, new).await else {
+        return 1;
+    };
+
+    println!("{} -> {}", old, new);
+    print!("{}", ReleaseDiff::between(&old_registry, &new_registry));
+    0
+}
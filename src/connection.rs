@@ -1,5 +1,8 @@
 use crate::hosts;
+use crate::packet_handler::interceptor::InterceptorChain;
 use crate::proxy::Proxy;
+use crate::session::SessionHandle;
+use crate::settings::Settings;
 use std::net::IpAddr;
 
 #[derive(Debug, PartialEq, Clone)]
@@ -17,6 +20,11 @@ pub struct Connection {
     pub game_resolved_ip: Option<IpAddr>,
     pub client_host: String,
     pub game_host: String,
+    pub settings: Settings,
+    // every frame goes through these before it's forwarded
+    pub interceptors: InterceptorChain,
+    // lets anything else in the program send packets into the session
+    pub session: SessionHandle,
 }
 
 impl Connection {
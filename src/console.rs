@@ -0,0 +1,238 @@
+use crate::logger::{ConsoleLogger, LogFilter};
+use crate::packet_handler::direction::Direction;
+use crate::packet_handler::expression::{self, Expression, ExpressionError};
+use crate::packet_handler::interceptor::InterceptorChain;
+use crate::packet_handler::message_registry::MessageRegistry;
+use crate::packet_handler::packet::Packet;
+use crate::packet_handler::recent;
+use crate::packet_handler::unknown_headers::UnknownHeaders;
+use crate::session::SessionHandle;
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use std::path::Path;
+use tokio::sync::mpsc::UnboundedSender;
+
+const HELP: &str = "\
+send [in|out] <expression>      {out:Chat}{s:\"hi\"}{i:0} or an escaped frame, out by default
+send [in|out] <Name> [values]   Chat {s:\"hi\"}{i:0}
+recent [count]                  the last packets seen, 20 by default
+log [in|out|both|none]          show or change which packets are logged
+status                          session, definitions and interceptors
+unknown                         write the unknown-header inventory now
+quit                            shut the proxy down";
+
+// A line-editing prompt on stdin for poking at the live session. It only ever goes through the
+// same handles the rest of the proxy uses, so it can't do anything a script couldn't.
+pub struct Console {
+    pub session: SessionHandle,
+    pub interceptors: InterceptorChain,
+    pub unknown_headers_file: String,
+    // told when the user asks to quit, main does the actual shutting down
+    pub shutdown: UnboundedSender<()>,
+}
+
+enum Flow {
+    Continue,
+    Quit,
+}
+
+impl Console {
+    // Runs on its own thread, reading stdin blocks.
+    pub fn spawn(self) {
+        std::thread::spawn(move || self.run());
+    }
+
+    fn run(self) {
+        let mut editor = match DefaultEditor::new() {
+            Ok(editor) => editor,
+            Err(e) => {
+                ConsoleLogger::warning(format!("Console unavailable: {}", e));
+                return;
+            }
+        };
+
+        loop {
+            let line = match editor.readline("hablog> ") {
+                Ok(line) => line,
+                // Ctrl-C and Ctrl-D mean the same as quit
+                Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => break,
+                Err(e) => {
+                    ConsoleLogger::warning(format!("Console stopped: {}", e));
+                    return;
+                }
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            let _ = editor.add_history_entry(line.as_str());
+
+            if let Flow::Quit = self.execute(&line) {
+                break;
+            }
+        }
+        let _ = self.shutdown.send(());
+    }
+
+    fn execute(&self, line: &str) -> Flow {
+        let line = line.trim();
+        let (command, args) = line.split_once(' ').unwrap_or((line, ""));
+
+        match command {
+            "help" | "?" => println!("{}", HELP),
+            "send" => match parse_send(args) {
+                Ok(packet) => {
+                    if let Err(e) = self.session.send(packet) {
+                        ConsoleLogger::warning(format!("Not sent: {}", e));
+                    }
+                }
+                Err(e) => ConsoleLogger::warning(format!("Not sent: {}", e)),
+            },
+            "recent" => {
+                let count = args.trim().parse().unwrap_or(20);
+                for packet in recent::latest(count) {
+                    println!("{}", packet);
+                }
+            }
+            "log" if args.trim().is_empty() => {
+                println!("logging {}", ConsoleLogger::packet_filter())
+            }
+            "log" => match LogFilter::parse(args) {
+                Some(filter) => {
+                    ConsoleLogger::set_packet_filter(filter);
+                    println!("logging {}", filter);
+                }
+                None => ConsoleLogger::warning("log takes in, out, both or none"),
+            },
+            "status" => self.status(),
+            "unknown" => dump_unknown_headers(&self.unknown_headers_file),
+            "quit" | "exit" => return Flow::Quit,
+            _ => ConsoleLogger::warning(format!("Unknown command '{}', try help", command)),
+        }
+        Flow::Continue
+    }
+
+    fn status(&self) {
+        let registry = MessageRegistry::current();
+        println!("session:      {}", describe_session(&self.session));
+        println!(
+            "release:      {}",
+            registry.release.as_deref().unwrap_or("unknown")
+        );
+        println!("definitions:  {}", registry.len());
+        println!("unknown:      {}", UnknownHeaders::snapshot().len());
+        println!("interceptors: {:?}", self.interceptors);
+        println!("logging:      {}", ConsoleLogger::packet_filter());
+    }
+}
+
+fn describe_session(session: &SessionHandle) -> &'static str {
+    if session.is_connected() {
+        "connected"
+    } else if session.has_ended() {
+        "disconnected"
+    } else {
+        "waiting for the client"
+    }
+}
+
+// The direction word is optional, expressions that say their own direction keep it.
+fn parse_send(args: &str) -> Result<Packet, ExpressionError> {
+    let args = args.trim();
+    let (direction, rest) = match args.split_once(' ') {
+        Some((word, rest)) => match Direction::parse(word) {
+            Some(direction) => (direction, rest.trim()),
+            None => (Direction::Out, args),
+        },
+        None => (Direction::Out, args),
+    };
+    if rest.is_empty() {
+        return Err(ExpressionError::MissingHeader);
+    }
+    if rest.starts_with('{') || rest.starts_with('[') {
+        return expression::parse_packet(rest, direction);
+    }
+
+    // a bare name (or header id) followed by values
+    let split = rest.find(['{', ' ']).unwrap_or(rest.len());
+    let (name, values) = rest.split_at(split);
+    let structured = format!(
+        "{{{}:{}}}{}",
+        direction.to_string().to_lowercase(),
+        name,
+        values.trim()
+    );
+    Expression::parse(&structured)?.to_packet(direction)
+}
+
+pub fn dump_unknown_headers(path: &str) {
+    let inventory = UnknownHeaders::snapshot();
+    if inventory.is_empty() {
+        return;
+    }
+    match inventory.write(Path::new(path)) {
+        Ok(()) => ConsoleLogger::normal(format!(
+            "Wrote {} unknown headers to {}",
+            inventory.len(),
+            path
+        )),
+        Err(e) => ConsoleLogger::warning(format!("Could not write {}: {}", path, e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::Proxy;
+    use crate::settings::Settings;
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[tokio::test]
+    async fn status_follows_the_socket() {
+        let session = SessionHandle::new();
+        assert_eq!(describe_session(&session), "waiting for the client");
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let (server_read, server_write) = server.into_split();
+        let injections = session.attach();
+        let forward = tokio::spawn(Proxy::forward_buffers(
+            server_read,
+            server_write,
+            Direction::Out,
+            Settings::default(),
+            crate::packet_handler::release::ReleaseGate::new(true),
+            InterceptorChain::new(),
+            injections.to_server,
+        ));
+        assert_eq!(describe_session(&session), "connected");
+
+        drop(client);
+        forward.await.unwrap();
+        assert_eq!(describe_session(&session), "disconnected");
+    }
+
+    #[test]
+    fn sends_by_header_with_values() {
+        let packet = parse_send("in 1066 {s:\"hi\"}{i:3}").unwrap();
+        assert_eq!(packet.direction, Direction::In);
+        assert_eq!(packet.get_header(), Ok(1066));
+        assert_eq!(packet.get_body().unwrap(), b"\0\x02hi\0\0\0\x03");
+    }
+
+    #[test]
+    fn expressions_and_escaped_frames() {
+        let packet = parse_send("{in:5}{b:true}").unwrap();
+        assert_eq!(packet.direction, Direction::In);
+        assert_eq!(packet.get_header(), Ok(5));
+
+        let packet = parse_send("[0][0][0][2][0][9]").unwrap();
+        assert_eq!(packet.direction, Direction::Out);
+        assert_eq!(packet.get_header(), Ok(9));
+
+        assert!(parse_send("").is_err());
+        assert!(parse_send("out").is_err());
+    }
+}
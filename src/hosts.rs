@@ -48,13 +48,9 @@ pub async fn resolve_host(connection: &mut Connection) -> Result<IpAddr, String>
     let host = format!("{}:{}", &connection.game_host, &connection.port);
     ConsoleLogger::normal(format!("Resolving host {}...", host));
     match host.to_socket_addrs() {
-        Ok(addrs) => {
+        Ok(mut addrs) => {
             let ip = addrs
-                .filter(|addr| match addr.ip() {
-                    IpAddr::V4(_) => true,
-                    IpAddr::V6(_) => false,
-                })
-                .next()
+                .find(|addr| addr.ip().is_ipv4())
                 .map(|addr| addr.ip())
                 .ok_or_else(|| "No suitable address found".to_string())?;
             ConsoleLogger::normal(format!("Resolved host to {}", ip));
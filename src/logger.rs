@@ -1,35 +1,157 @@
+use crate::packet_handler::direction::Direction;
+use crate::packet_handler::inference::StructureGuess;
 use crate::packet_handler::packet::Packet;
+use crate::packet_handler::packet_value::PacketValue;
 use std::{
     fmt::{Debug, Display},
-    io::Write,
+    io::{IsTerminal, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        RwLock,
+    },
 };
 use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
 
+lazy_static::lazy_static! {
+    static ref PACKET_FILTER: RwLock<LogFilter> = RwLock::new(LogFilter::Both);
+}
+
+// One-off commands print their results on stdout, so the log gets out of the way.
+static LOG_TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+// Which directions end up in the packet log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFilter {
+    In,
+    Out,
+    Both,
+    // nothing, handy while typing in the console
+    None,
+}
+
+impl LogFilter {
+    pub fn parse(value: &str) -> Option<LogFilter> {
+        match value.trim().to_lowercase().as_str() {
+            "both" | "all" => Some(LogFilter::Both),
+            "none" | "off" => Some(LogFilter::None),
+            other => match Direction::parse(other)? {
+                Direction::In => Some(LogFilter::In),
+                Direction::Out => Some(LogFilter::Out),
+            },
+        }
+    }
+
+    pub fn allows(&self, direction: Direction) -> bool {
+        matches!(
+            (self, direction),
+            (LogFilter::Both, _)
+                | (LogFilter::In, Direction::In)
+                | (LogFilter::Out, Direction::Out)
+        )
+    }
+}
+
+impl Display for LogFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LogFilter::In => write!(f, "in"),
+            LogFilter::Out => write!(f, "out"),
+            LogFilter::Both => write!(f, "both"),
+            LogFilter::None => write!(f, "none"),
+        }
+    }
+}
+
 pub struct ConsoleLogger {}
 
 impl ConsoleLogger {
+    pub fn set_packet_filter(filter: LogFilter) {
+        *PACKET_FILTER.write().unwrap() = filter;
+    }
+
+    pub fn packet_filter() -> LogFilter {
+        *PACKET_FILTER.read().unwrap()
+    }
+
+    pub fn logs_packets(direction: Direction) -> bool {
+        Self::packet_filter().allows(direction)
+    }
+
+    pub fn log_to_stderr() {
+        LOG_TO_STDERR.store(true, Ordering::SeqCst);
+    }
+
+    // stderr only gets colors when someone is looking at it
+    fn log_stream() -> StandardStream {
+        if !LOG_TO_STDERR.load(Ordering::SeqCst) {
+            return StandardStream::stdout(ColorChoice::Always);
+        }
+        match std::io::stderr().is_terminal() {
+            true => StandardStream::stderr(ColorChoice::Always),
+            false => StandardStream::stderr(ColorChoice::Never),
+        }
+    }
+
+    // Each direction gets its own look so a busy log is easy to follow.
+    fn direction_color(direction: Direction) -> Color {
+        match direction {
+            Direction::In => Color::Red,
+            Direction::Out => Color::Magenta,
+        }
+    }
+
     fn print_log<T: Display>(level: &str, message: T, color: Color) {
-        let mut stdout = StandardStream::stdout(ColorChoice::Always);
+        let mut stdout = Self::log_stream();
         let log_prefix = format!("[hablog]{}", level);
 
         stdout
             .set_color(ColorSpec::new().set_bold(true).set_fg(Some(color)))
             .unwrap();
         write!(&mut stdout, "{}", log_prefix).unwrap();
-        stdout.set_color(&ColorSpec::new().set_reset(true)).unwrap();
+        stdout.set_color(ColorSpec::new().set_reset(true)).unwrap();
         writeln!(&mut stdout, ":: {}", message).unwrap();
     }
 
-    pub fn log_packet<T: Debug>(mut packet: Packet, body: &[u8]) {
+    // Shows the decoded values when the message has a known or guessed structure, the raw body otherwise.
+    // Guessed layouts are marked with a ~ so they aren't mistaken for documented ones.
+    pub fn log_packet(
+        packet: Packet,
+        body: &[u8],
+        values: Option<&[PacketValue]>,
+        guess: Option<&StructureGuess>,
+    ) {
         let mut stdout = StandardStream::stdout(ColorChoice::Always);
-        let log_prefix = format!("[{}]", packet.direction);
-        let header = format!("[{}]", packet.get_header());
-        let name = format!("[{}]", packet.name.unwrap_or_default());
+        let direction_color = Self::direction_color(packet.direction);
+        // padded so In and Out line up
+        let log_prefix = format!("[{:<3}]", packet.direction);
+        let header = format!("[{}]", packet.header.unwrap_or_default());
+        let name = match guess {
+            Some(guess) => format!(
+                "[{}][~{} {:.0}%]",
+                packet.name.unwrap_or_default(),
+                guess.signature,
+                guess.confidence * 100.0
+            ),
+            None => format!("[{}]", packet.name.unwrap_or_default()),
+        };
 
-        let body = format!("[{}]", filter_special_chars(body));
+        let body = match values {
+            Some(values) => format!(
+                "[{}]",
+                values
+                    .iter()
+                    .map(|value| value.to_string())
+                    .collect::<String>()
+            ),
+            None => format!("[{}]", filter_special_chars(body)),
+        };
 
         stdout
-            .set_color(ColorSpec::new().set_bold(true).set_fg(Some(Color::Red)))
+            .set_color(
+                ColorSpec::new()
+                    .set_bold(true)
+                    .set_fg(Some(direction_color)),
+            )
             .unwrap();
         write!(&mut stdout, "{}", log_prefix).unwrap();
         stdout.set_color(ColorSpec::new().set_reset(true)).unwrap();
@@ -57,6 +179,14 @@ impl ConsoleLogger {
         }
     }
 
+    pub fn log_raw(direction: Direction, bytes: &[u8]) {
+        Self::print_log(
+            &format!("[{}][raw]", direction),
+            hex::encode(bytes),
+            Self::direction_color(direction),
+        );
+    }
+
     pub fn info<T: Display>(message: T) {
         Self::print_log("", message, Color::Blue);
     }
@@ -72,7 +202,7 @@ impl ConsoleLogger {
     pub fn error<T: Debug>(error: T) {
         let error_message = format!("{:?}", error);
         let log_message = format!("[hablog]:: Error: {}", error_message);
-        let mut stdout = StandardStream::stdout(ColorChoice::Always);
+        let mut stdout = Self::log_stream();
 
         stdout
             .set_color(
@@ -84,7 +214,7 @@ impl ConsoleLogger {
             )
             .unwrap();
         writeln!(&mut stdout, "{}", log_message).unwrap();
-        stdout.set_color(&ColorSpec::new().set_reset(true)).unwrap();
+        stdout.set_color(ColorSpec::new().set_reset(true)).unwrap();
     }
     pub fn print_dashes() {
         let mut stdout = StandardStream::stdout(ColorChoice::Always);
@@ -108,7 +238,7 @@ impl ConsoleLogger {
             .unwrap();
         }
 
-        stdout.set_color(&ColorSpec::new().set_reset(true)).unwrap();
+        stdout.set_color(ColorSpec::new().set_reset(true)).unwrap();
     }
     pub fn print_rainbow_text<T: Display>(message: T) {
         let mut stdout = StandardStream::stdout(ColorChoice::Always);
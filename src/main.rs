@@ -1,32 +1,100 @@
+mod commands;
 mod connection;
+mod console;
 pub mod hosts;
 pub mod logger;
 pub mod proxy;
+pub mod session;
+pub mod settings;
 pub mod packet_handler {
+    pub mod definition_cache;
+    pub mod definitions;
+    pub mod direction;
+    pub mod expression;
+    pub mod header_translation;
+    pub mod inference;
+    pub mod interceptor;
+    pub mod message_registry;
+    pub mod message_source;
+    pub mod overrides;
     pub mod packet;
+    pub mod packet_builder;
+    pub mod packet_error;
+    #[allow(clippy::module_inception)]
     pub mod packet_handler;
+    pub mod packet_value;
+    pub mod plugin;
+    pub mod reassembler;
+    pub mod recent;
+    pub mod release;
+    pub mod release_diff;
+    pub mod rewrite;
+    pub mod rules;
+    pub mod scripting;
+    pub mod structure;
+    #[cfg(test)]
+    pub mod test_support;
+    pub mod unknown_headers;
 }
 use connection::Connection;
+use console::{dump_unknown_headers, Console};
 use logger::ConsoleLogger;
+use packet_handler::definitions;
+use packet_handler::interceptor::InterceptorChain;
+use packet_handler::packet_handler::PacketHandler;
+use packet_handler::plugin;
+use packet_handler::rules::RulesInterceptor;
+use packet_handler::scripting;
+use session::SessionHandle;
+use settings::Settings;
+use std::io::IsTerminal;
 
 use tokio::signal::unix::{signal, SignalKind};
 
 #[tokio::main]
 
 async fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        ConsoleLogger::log_to_stderr();
+    }
+    let settings = Settings::load(settings::SETTINGS_FILE);
+    if !args.is_empty() {
+        std::process::exit(commands::run(&args, &settings).await);
+    }
+
     check_if_root();
-    packet_handler::packet_handler::PacketHandler::fetch_packets().await;
+    ConsoleLogger::set_packet_filter(settings.log_filter);
+    definitions::install(&settings, &settings.release).await;
+    for (name, signature) in &settings.structures {
+        if let Err(e) = PacketHandler::register_structure(name, signature) {
+            ConsoleLogger::warning(format!("Ignoring structure for {}: {}", name, e));
+        }
+    }
+    let unknown_headers_file = settings.unknown_headers_file.clone();
     ConsoleLogger::normal("Preparing connection...");
     let game_host = String::from("game-us.habbo.com");
     let port = 38101;
     let client_host = String::from("127.0.0.1");
+    let interceptors = InterceptorChain::new();
+    let session = SessionHandle::new();
+    interceptors.add(RulesInterceptor::new(&settings.rules_file));
+    for script in scripting::load_scripts(&settings.scripts_dir, &session) {
+        interceptors.add(script);
+    }
+    for plugin in plugin::load_plugins(&settings.plugins_dir, settings.plugin_limits, &session) {
+        interceptors.add(plugin);
+    }
     let mut connection = Connection {
         game_resolved_ip: None,
         port,
-        game_host,
+        game_host: game_host.clone(),
         connection_state: connection::ConnectionState::Disconnected,
         // packet_handler: &PACKET_HANDLER,
         client_host,
+        settings,
+        interceptors: interceptors.clone(),
+        session: session.clone(),
     };
 
     ConsoleLogger::normal("Initializing PacketHandler...");
@@ -37,9 +105,34 @@ async fn main() {
         connection.start().await;
     });
 
+    let (shutdown, mut shutdown_requested) = tokio::sync::mpsc::unbounded_channel();
+    // no prompt when there's nobody to type into it, e.g. running as a service
+    if std::io::stdin().is_terminal() {
+        Console {
+            session,
+            interceptors,
+            unknown_headers_file: unknown_headers_file.clone(),
+            shutdown,
+        }
+        .spawn();
+    }
+
     let mut term_signal = signal(SignalKind::terminate()).expect("Failed to set up signal handler");
-    term_signal.recv().await;
+    let mut dump_signal =
+        signal(SignalKind::user_defined1()).expect("Failed to set up signal handler");
+    loop {
+        tokio::select! {
+            _ = term_signal.recv() => break,
+            _ = tokio::signal::ctrl_c() => break,
+            _ = shutdown_requested.recv() => break,
+            // kill -USR1 <pid> dumps without stopping
+            _ = dump_signal.recv() => dump_unknown_headers(&unknown_headers_file),
+        }
+    }
 
+    dump_unknown_headers(&unknown_headers_file);
+    // don't leave the game host pointing at us once we're gone
+    hosts::remove_proxy_if_exists(&game_host).await;
     println!("Closing connection...");
 }
 
@@ -0,0 +1,181 @@
+use crate::logger::ConsoleLogger;
+use crate::packet_handler::definitions::{self, DefinitionError};
+use crate::packet_handler::message_registry::MessageInfo;
+use serde_json::{json, Value};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Bump this whenever the layout of a cache file changes, older files are then thrown away.
+const CACHE_VERSION: u64 = 1;
+
+// Definitions for each release, one file per release:
+//
+//   {"cache_version": 1, "release": "...", "fetched_at": 1689000000, "definitions": {...}}
+//
+// where "definitions" is the API response untouched.
+// What a cache file held. Expired definitions are still handed out, they beat having none
+// when the API can't be reached.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CachedDefinitions {
+    pub messages: Vec<MessageInfo>,
+    pub expired: bool,
+    // hours since the definitions were fetched
+    pub age_hours: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct DefinitionCache {
+    directory: PathBuf,
+    max_age: Duration,
+}
+
+impl DefinitionCache {
+    pub fn new(directory: &str, max_age: Duration) -> Self {
+        DefinitionCache {
+            directory: PathBuf::from(directory),
+            max_age,
+        }
+    }
+
+    pub fn path(&self, release: &str) -> PathBuf {
+        self.directory.join(format!("messages-{}.json", release))
+    }
+
+    // A cache file from an older hablog or simply broken is deleted so it can't get in the way
+    // again. One that's only old is kept, it's the copy we fall back on when offline.
+    pub fn read(&self, release: &str) -> Result<CachedDefinitions, DefinitionError> {
+        let path = self.path(release);
+        let contents = std::fs::read_to_string(&path)?;
+
+        let result = serde_json::from_str::<Value>(&contents)
+            .map_err(DefinitionError::from)
+            .and_then(|json| self.validate(&json, release));
+        if let Err(e) = &result {
+            ConsoleLogger::warning(format!("Discarding cache {}: {}", path.display(), e));
+            let _ = std::fs::remove_file(&path);
+        }
+        result
+    }
+
+    fn validate(&self, json: &Value, release: &str) -> Result<CachedDefinitions, DefinitionError> {
+        let version = json.get("cache_version").and_then(|v| v.as_u64());
+        if version != Some(CACHE_VERSION) {
+            return Err(DefinitionError::Schema(format!(
+                "cache version {:?}, expected {}",
+                version, CACHE_VERSION
+            )));
+        }
+        if json.get("release").and_then(|v| v.as_str()) != Some(release) {
+            return Err(DefinitionError::Schema(format!(
+                "cache is not for {}",
+                release
+            )));
+        }
+
+        let fetched_at = json
+            .get("fetched_at")
+            .and_then(|v| v.as_u64())
+            .ok_or_else(|| DefinitionError::Schema("missing \"fetched_at\"".to_owned()))?;
+        let age = now().saturating_sub(fetched_at);
+
+        let definitions = json
+            .get("definitions")
+            .ok_or_else(|| DefinitionError::Schema("missing \"definitions\"".to_owned()))?;
+        Ok(CachedDefinitions {
+            messages: definitions::parse_sulek(definitions)?,
+            expired: age > self.max_age.as_secs(),
+            age_hours: age / 3600,
+        })
+    }
+
+    // Written next to the real file and renamed over it, so a crash never leaves half a cache behind.
+    pub fn write(&self, release: &str, definitions: &Value) -> std::io::Result<()> {
+        let contents = json!({
+            "cache_version": CACHE_VERSION,
+            "release": release,
+            "fetched_at": now(),
+            "definitions": definitions,
+        });
+
+        std::fs::create_dir_all(&self.directory)?;
+        let path = self.path(release);
+        let temporary = path.with_extension("json.tmp");
+        std::fs::write(&temporary, contents.to_string())?;
+        std::fs::rename(&temporary, &path)
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet_handler::direction::Direction;
+
+    const RELEASE: &str = "MAC63-202307041149-55201637";
+
+    fn cache(name: &str, max_age: Duration) -> DefinitionCache {
+        let directory =
+            std::env::temp_dir().join(format!("hablog-cache-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        DefinitionCache::new(directory.to_str().unwrap(), max_age)
+    }
+
+    fn definitions() -> Value {
+        json!({"messages": {"incoming": [{"id": 1066, "name": "Chat"}], "outgoing": []}})
+    }
+
+    #[test]
+    fn round_trips_definitions() {
+        let cache = cache("round-trip", Duration::from_secs(3600));
+        cache.write(RELEASE, &definitions()).unwrap();
+
+        let cached = cache.read(RELEASE).unwrap();
+        assert_eq!(
+            cached.messages,
+            vec![MessageInfo::new(Direction::In, 1066, "Chat")]
+        );
+        assert!(!cached.expired);
+        assert!(cache.read("PRODUCTION-202307041149-55201637").is_err());
+    }
+
+    #[test]
+    fn throws_away_corrupt_files() {
+        let cache = cache("corrupt", Duration::from_secs(3600));
+        cache.write(RELEASE, &definitions()).unwrap();
+        std::fs::write(cache.path(RELEASE), "{\"cache_version\": 1, \"rel").unwrap();
+
+        assert!(matches!(cache.read(RELEASE), Err(DefinitionError::Json(_))));
+        assert!(!cache.path(RELEASE).exists());
+    }
+
+    #[test]
+    fn throws_away_old_versions_but_keeps_expired_files() {
+        let cache = cache("stale", Duration::from_secs(3600));
+        std::fs::create_dir_all(&cache.directory).unwrap();
+
+        std::fs::write(cache.path(RELEASE), definitions().to_string()).unwrap();
+        assert!(matches!(
+            cache.read(RELEASE),
+            Err(DefinitionError::Schema(_))
+        ));
+        assert!(!cache.path(RELEASE).exists());
+
+        let stale = json!({
+            "cache_version": CACHE_VERSION,
+            "release": RELEASE,
+            "fetched_at": now() - 7200,
+            "definitions": definitions(),
+        });
+        std::fs::write(cache.path(RELEASE), stale.to_string()).unwrap();
+        let cached = cache.read(RELEASE).unwrap();
+        assert!(cached.expired);
+        assert_eq!(cached.age_hours, 2);
+        assert!(cache.path(RELEASE).exists());
+    }
+}
@@ -0,0 +1,353 @@
+use crate::logger::ConsoleLogger;
+use crate::packet_handler::definition_cache::DefinitionCache;
+use crate::packet_handler::direction::Direction;
+use crate::packet_handler::message_registry::{MessageInfo, MessageRegistry};
+use crate::packet_handler::message_source::{
+    FileSource, GEarthSource, MessageSource, SulekApiSource,
+};
+use crate::packet_handler::overrides::{Overrides, Unapplied};
+use crate::settings::{Settings, SourceConfig};
+use serde_json::Value;
+use std::fmt;
+use std::path::Path;
+
+#[derive(Debug)]
+pub enum DefinitionError {
+    Io(std::io::Error),
+    Http(reqwest::Error),
+    Json(serde_json::Error),
+    Schema(String),
+}
+
+impl fmt::Display for DefinitionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DefinitionError::Io(e) => write!(f, "{}", e),
+            DefinitionError::Http(e) => write!(f, "{}", e),
+            DefinitionError::Json(e) => write!(f, "invalid JSON: {}", e),
+            DefinitionError::Schema(message) => write!(f, "unexpected format: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for DefinitionError {}
+
+impl From<std::io::Error> for DefinitionError {
+    fn from(e: std::io::Error) -> Self {
+        DefinitionError::Io(e)
+    }
+}
+
+impl From<reqwest::Error> for DefinitionError {
+    fn from(e: reqwest::Error) -> Self {
+        DefinitionError::Http(e)
+    }
+}
+
+impl From<serde_json::Error> for DefinitionError {
+    fn from(e: serde_json::Error) -> Self {
+        DefinitionError::Json(e)
+    }
+}
+
+// Builds the registry from every configured source and installs it. Nothing here can stop the
+// proxy from starting: sources that fail are skipped, and with none left the packets just go unnamed.
+// When the API was answered from the cache, fresh definitions are fetched in the background.
+pub async fn install(settings: &Settings, release: &str) {
+    let sources = sources(settings, release, true);
+    let mut registry = merge(&sources).await;
+    apply_overrides(settings, &mut registry);
+    registry.release = Some(release.to_owned());
+    if registry.is_empty() {
+        ConsoleLogger::warning("No message definitions available, packets will be unnamed");
+    }
+    MessageRegistry::install(registry);
+
+    if sources.iter().any(|source| source.wants_refresh()) {
+        let settings = settings.clone();
+        let release = release.to_owned();
+        tokio::spawn(async move { refresh_in_background(settings, release).await });
+    }
+}
+
+// Definitions for one release on their own, for comparing releases rather than running the proxy.
+// A path to a definitions file works too. Unlike `install`, failing to load is an error here.
+pub async fn load_release(
+    settings: &Settings,
+    release: &str,
+) -> Result<MessageRegistry, DefinitionError> {
+    let source: Box<dyn MessageSource> = if Path::new(release).is_file() {
+        Box::new(FileSource::new(release))
+    } else {
+        Box::new(SulekApiSource::new(
+            release,
+            DefinitionCache::new(&settings.cache_dir, settings.cache_max_age),
+            true,
+        ))
+    };
+
+    let mut registry = MessageRegistry::new();
+    for message in source.load().await? {
+        merge_message(&mut registry, message);
+    }
+    registry.release = Some(release.to_owned());
+    Ok(registry)
+}
+
+// Sources in order of precedence, highest first. A local messages file always comes first.
+pub fn sources(settings: &Settings, release: &str, use_cache: bool) -> Vec<Box<dyn MessageSource>> {
+    let mut sources: Vec<Box<dyn MessageSource>> = Vec::new();
+    if let Some(path) = &settings.messages_file {
+        sources.push(Box::new(FileSource::new(path)));
+    }
+
+    for source in &settings.sources {
+        sources.push(match source {
+            SourceConfig::Sulek => Box::new(SulekApiSource::new(
+                release,
+                DefinitionCache::new(&settings.cache_dir, settings.cache_max_age),
+                use_cache,
+            )),
+            SourceConfig::File(path) => Box::new(FileSource::new(path)),
+            SourceConfig::GEarth(path) => Box::new(GEarthSource::new(path)),
+        });
+    }
+    sources
+}
+
+// Earlier sources win: a message is only taken from a later source when neither its header nor its
+// name is taken yet in that direction. Later sources can still fill in a hash or structure the
+// winning entry for the same message didn't have.
+pub async fn merge(sources: &[Box<dyn MessageSource>]) -> MessageRegistry {
+    let mut registry = MessageRegistry::new();
+
+    for source in sources {
+        let messages = match source.load().await {
+            Ok(messages) => messages,
+            Err(e) => {
+                ConsoleLogger::warning(format!("Could not load {}: {}", source.describe(), e));
+                continue;
+            }
+        };
+        ConsoleLogger::normal(format!(
+            "Loaded {} message definitions from {}",
+            messages.len(),
+            source.describe()
+        ));
+
+        for message in messages {
+            merge_message(&mut registry, message);
+        }
+    }
+
+    registry
+}
+
+fn merge_message(registry: &mut MessageRegistry, message: MessageInfo) {
+    let by_header = registry.get(message.direction, message.header).cloned();
+    let by_name = registry.find(message.direction, &message.name).cloned();
+
+    match (by_header, by_name) {
+        (None, None) => registry.insert(message),
+        (Some(existing), _) if existing.name == message.name => {
+            let merged = MessageInfo {
+                hash: existing.hash.clone().or(message.hash),
+                structure: existing.structure.clone().or(message.structure),
+                ..existing
+            };
+            registry.insert(merged);
+        }
+        _ => {}
+    }
+}
+
+// Swaps in fresh definitions once they arrive, unless the client has moved on to another release meanwhile.
+async fn refresh_in_background(settings: Settings, release: String) {
+    let mut registry = merge(&sources(&settings, &release, false)).await;
+    apply_overrides(&settings, &mut registry);
+    if registry.is_empty()
+        || MessageRegistry::current().release.as_deref() != Some(release.as_str())
+    {
+        return;
+    }
+    registry.release = Some(release.clone());
+    ConsoleLogger::normal(format!("Refreshed message definitions for {}", release));
+    MessageRegistry::install(registry);
+}
+
+// Read again on every load, so edits show up the next time definitions are loaded or refreshed.
+fn apply_overrides(settings: &Settings, registry: &mut MessageRegistry) {
+    let path = Path::new(&settings.overrides_file);
+    if !path.exists() {
+        return;
+    }
+
+    match Overrides::load(path) {
+        Ok(overrides) => {
+            for unapplied in overrides.apply(registry) {
+                ConsoleLogger::warning(match unapplied {
+                    Unapplied::Unmatched(entry) => format!(
+                        "Override for {} {:?} matches no message",
+                        entry.direction, entry.target
+                    ),
+                    Unapplied::NameTaken { entry, owner } => format!(
+                        "Override for {} {:?} can't rename it to {}, header {} already has that name",
+                        entry.direction,
+                        entry.target,
+                        entry.name.as_deref().unwrap_or_default(),
+                        owner
+                    ),
+                });
+            }
+            ConsoleLogger::normal(format!(
+                "Applied {} overrides from {}",
+                overrides.len(),
+                path.display()
+            ));
+        }
+        Err(e) => ConsoleLogger::warning(format!("Could not load {}: {}", path.display(), e)),
+    }
+}
+
+// The sulek.dev shape: {"messages": {"incoming": [{"id": 1, "name": "...", "hash": "..."}], "outgoing": [...]}}
+pub fn parse_sulek(json: &Value) -> Result<Vec<MessageInfo>, DefinitionError> {
+    let messages = json
+        .get("messages")
+        .ok_or_else(|| DefinitionError::Schema("missing \"messages\"".to_owned()))?;
+
+    let mut parsed = Vec::new();
+    for (key, direction) in [("incoming", Direction::In), ("outgoing", Direction::Out)] {
+        let Some(packets) = messages.get(key).and_then(|packets| packets.as_array()) else {
+            return Err(DefinitionError::Schema(format!("missing \"{}\" list", key)));
+        };
+
+        for packet in packets {
+            let name = packet.get("name").and_then(|name| name.as_str());
+            let header = packet
+                .get("id")
+                .and_then(|header| header.as_u64())
+                .filter(|header| *header <= u16::MAX as u64);
+            let (Some(name), Some(header)) = (name, header) else {
+                return Err(DefinitionError::Schema(format!(
+                    "bad {} entry {}",
+                    key, packet
+                )));
+            };
+
+            let mut message = MessageInfo::new(direction, header as u16, name);
+            message.hash = packet
+                .get("hash")
+                .and_then(|hash| hash.as_str())
+                .map(str::to_owned);
+            parsed.push(message);
+        }
+    }
+
+    Ok(parsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet_handler::message_source::LoadFuture;
+    use serde_json::json;
+
+    struct Fixed(Vec<MessageInfo>);
+
+    impl MessageSource for Fixed {
+        fn describe(&self) -> String {
+            String::from("fixed")
+        }
+
+        fn load(&self) -> LoadFuture<'_> {
+            let messages = self.0.clone();
+            Box::pin(async move { Ok(messages) })
+        }
+    }
+
+    struct Broken;
+
+    impl MessageSource for Broken {
+        fn describe(&self) -> String {
+            String::from("broken")
+        }
+
+        fn load(&self) -> LoadFuture<'_> {
+            Box::pin(async { Err(DefinitionError::Schema(String::from("nope"))) })
+        }
+    }
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    #[test]
+    fn parses_both_directions() {
+        let messages = parse_sulek(&json!({
+            "messages": {
+                "incoming": [{"id": 1066, "name": "Chat", "hash": "abc"}],
+                "outgoing": [{"id": 1066, "name": "MoveAvatar"}, {"id": 4000, "name": "ClientHello"}]
+            }
+        }))
+        .unwrap();
+
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[0], {
+            let mut chat = MessageInfo::new(Direction::In, 1066, "Chat");
+            chat.hash = Some(String::from("abc"));
+            chat
+        });
+        assert_eq!(messages[2].direction, Direction::Out);
+    }
+
+    #[test]
+    fn rejects_the_wrong_shape() {
+        assert!(matches!(
+            parse_sulek(&json!({"incoming": []})),
+            Err(DefinitionError::Schema(_))
+        ));
+        assert!(matches!(
+            parse_sulek(&json!({"messages": {"incoming": [{"id": "x"}], "outgoing": []}})),
+            Err(DefinitionError::Schema(_))
+        ));
+    }
+
+    #[test]
+    fn earlier_sources_take_precedence() {
+        let mut structured = MessageInfo::new(Direction::In, 1066, "Chat");
+        structured.structure = Some(String::from("isii"));
+        let sources: Vec<Box<dyn MessageSource>> = vec![
+            Box::new(Fixed(vec![
+                MessageInfo::new(Direction::In, 1066, "Chat"),
+                MessageInfo::new(Direction::Out, 5, "Ping"),
+            ])),
+            Box::new(Broken),
+            Box::new(Fixed(vec![
+                structured,
+                // header taken
+                MessageInfo::new(Direction::Out, 5, "Pong"),
+                // name taken
+                MessageInfo::new(Direction::Out, 6, "Ping"),
+                MessageInfo::new(Direction::Out, 7, "Latency"),
+            ])),
+        ];
+
+        let registry = block_on(merge(&sources));
+
+        assert_eq!(registry.len(), 3);
+        assert_eq!(registry.get(Direction::Out, 5).unwrap().name, "Ping");
+        assert!(registry.get(Direction::Out, 6).is_none());
+        assert_eq!(registry.get(Direction::Out, 7).unwrap().name, "Latency");
+        assert_eq!(
+            registry
+                .get(Direction::In, 1066)
+                .unwrap()
+                .structure
+                .as_deref(),
+            Some("isii")
+        );
+    }
+}
@@ -0,0 +1,28 @@
+use std::fmt;
+
+// Which way a packet travels. In is server to client, Out is client to server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Direction {
+    In,
+    Out,
+}
+
+impl Direction {
+    // Accepts the spellings people actually type: in/out, incoming/outgoing, any case.
+    pub fn parse(value: &str) -> Option<Direction> {
+        match value.trim().to_lowercase().as_str() {
+            "in" | "incoming" => Some(Direction::In),
+            "out" | "outgoing" => Some(Direction::Out),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Direction::In => f.pad("In"),
+            Direction::Out => f.pad("Out"),
+        }
+    }
+}
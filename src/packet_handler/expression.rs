@@ -0,0 +1,427 @@
+use crate::packet_handler::direction::Direction;
+use crate::packet_handler::packet::Packet;
+use crate::packet_handler::packet_builder::PacketBuilder;
+use crate::packet_handler::packet_value::PacketValue;
+use std::fmt;
+
+// G-Earth has two ways of writing a packet down:
+//
+//   escaped:    [0][0][0][11][4][26][0][5]hello[0][0][0][0]
+//   structured: {out:Chat}{s:"hello"}{i:0}{b:false}
+//
+// The escaped form is the whole frame byte for byte, printable bytes as themselves and everything
+// else as [n]. The structured form names the message (or gives its header id) and lists typed values.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExpressionError {
+    UnexpectedEnd,
+    UnexpectedChar { found: char, position: usize },
+    UnknownType(String),
+    InvalidValue { kind: String, value: String },
+    MissingHeader,
+    UnknownMessage(String),
+    TooShort,
+}
+
+impl fmt::Display for ExpressionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExpressionError::UnexpectedEnd => write!(f, "expression ended unexpectedly"),
+            ExpressionError::UnexpectedChar { found, position } => {
+                write!(f, "unexpected '{}' at position {}", found, position)
+            }
+            ExpressionError::UnknownType(kind) => write!(f, "unknown value type '{}'", kind),
+            ExpressionError::InvalidValue { kind, value } => {
+                write!(f, "'{}' is not a valid {} value", value, kind)
+            }
+            ExpressionError::MissingHeader => {
+                write!(
+                    f,
+                    "expression must start with {{in:..}}, {{out:..}} or {{h:..}}"
+                )
+            }
+            ExpressionError::UnknownMessage(name) => write!(f, "unknown message '{}'", name),
+            ExpressionError::TooShort => write!(f, "a packet needs at least a length and a header"),
+        }
+    }
+}
+
+impl std::error::Error for ExpressionError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HeaderRef {
+    Id(u16),
+    Name(String),
+}
+
+impl fmt::Display for HeaderRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HeaderRef::Id(id) => write!(f, "{}", id),
+            HeaderRef::Name(name) => write!(f, "{}", name),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Expression {
+    // None when written as {h:..}, which doesn't say which way the packet goes
+    pub direction: Option<Direction>,
+    pub header: HeaderRef,
+    pub values: Vec<PacketValue>,
+}
+
+impl Expression {
+    pub fn parse(input: &str) -> Result<Expression, ExpressionError> {
+        let mut parser = Parser::new(input);
+        parser.skip_whitespace();
+
+        let (kind, header) = parser.token()?.ok_or(ExpressionError::MissingHeader)?;
+        let direction = match kind.as_str() {
+            "in" => Some(Direction::In),
+            "out" => Some(Direction::Out),
+            "h" => None,
+            _ => return Err(ExpressionError::MissingHeader),
+        };
+        let header = match header.parse::<u16>() {
+            Ok(id) => HeaderRef::Id(id),
+            Err(_) if kind == "h" => {
+                return Err(ExpressionError::InvalidValue {
+                    kind: kind.clone(),
+                    value: header,
+                })
+            }
+            Err(_) => HeaderRef::Name(header),
+        };
+
+        let mut values = Vec::new();
+        while let Some((kind, value)) = parser.token()? {
+            values.push(parse_value(&kind, &value)?);
+        }
+
+        Ok(Expression {
+            direction,
+            header,
+            values,
+        })
+    }
+
+    // Builds the frame, looking the name up in the loaded definitions when we don't have an id.
+    pub fn to_packet(&self, default_direction: Direction) -> Result<Packet, ExpressionError> {
+        let direction = self.direction.unwrap_or(default_direction);
+        let builder = match &self.header {
+            HeaderRef::Id(id) => PacketBuilder::new(*id, direction),
+            HeaderRef::Name(name) => PacketBuilder::from_name(name, direction)
+                .ok_or_else(|| ExpressionError::UnknownMessage(name.clone()))?,
+        };
+
+        Ok(self
+            .values
+            .iter()
+            .fold(builder, |builder, value| builder.append_value(value))
+            .build())
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.direction {
+            Some(direction) => write!(
+                f,
+                "{{{}:{}}}",
+                direction.to_string().to_lowercase(),
+                self.header
+            )?,
+            None => write!(f, "{{h:{}}}", self.header)?,
+        }
+        for value in &self.values {
+            write!(f, "{}", value)?;
+        }
+        Ok(())
+    }
+}
+
+// Accepts either form. Escaped packets get their length prefix recomputed like G-Earth does,
+// so hand edited bodies don't need the length fixed up by hand.
+pub fn parse_packet(input: &str, default_direction: Direction) -> Result<Packet, ExpressionError> {
+    let input = input.trim();
+    if input.starts_with('{') {
+        return Expression::parse(input)?.to_packet(default_direction);
+    }
+
+    let mut bytes = unescape(input)?;
+    if bytes.len() < 6 {
+        return Err(ExpressionError::TooShort);
+    }
+    let length = (bytes.len() - 4) as u32;
+    bytes[0..4].copy_from_slice(&length.to_be_bytes());
+    Ok(Packet::new(Some(bytes), None, None, default_direction))
+}
+
+pub fn escape(bytes: &[u8]) -> String {
+    let mut packet_string = String::new();
+
+    for x in bytes {
+        // Check if byte is a control character or not
+        if *x < 32 || *x == 93 || *x == 91 || *x == 125 || *x == 123 || *x == 127 {
+            packet_string.push('[');
+            packet_string.push_str(&x.to_string());
+            packet_string.push(']');
+        } else {
+            packet_string.push(*x as char);
+        }
+    }
+
+    packet_string
+}
+
+// Reverse of `escape`. A '[' that isn't followed by a byte value and ']' is taken literally.
+// Every other character is one byte in latin-1, like G-Earth writes them, so anything past that
+// (pasted chat text, usually) has to go in as a structured {s:..} instead.
+pub fn unescape(input: &str) -> Result<Vec<u8>, ExpressionError> {
+    let mut bytes = Vec::new();
+    let chars = input.chars().collect::<Vec<_>>();
+    let mut index = 0;
+
+    while index < chars.len() {
+        if chars[index] == '[' {
+            let digits = chars[index + 1..]
+                .iter()
+                .take_while(|c| c.is_ascii_digit())
+                .collect::<String>();
+            let closing = index + 1 + digits.len();
+            if !digits.is_empty() && chars.get(closing) == Some(&']') {
+                if let Ok(byte) = digits.parse::<u8>() {
+                    bytes.push(byte);
+                    index = closing + 1;
+                    continue;
+                }
+            }
+        }
+
+        let c = chars[index];
+        let byte = u8::try_from(c as u32).map_err(|_| ExpressionError::UnexpectedChar {
+            found: c,
+            position: index,
+        })?;
+        bytes.push(byte);
+        index += 1;
+    }
+
+    Ok(bytes)
+}
+
+fn parse_value(kind: &str, value: &str) -> Result<PacketValue, ExpressionError> {
+    let invalid = || ExpressionError::InvalidValue {
+        kind: kind.to_owned(),
+        value: value.to_owned(),
+    };
+
+    match kind {
+        "i" => value.parse().map(PacketValue::Int).map_err(|_| invalid()),
+        "u" => value
+            .parse::<u16>()
+            .map(PacketValue::Short)
+            .map_err(|_| invalid()),
+        "l" => value.parse().map(PacketValue::Long).map_err(|_| invalid()),
+        "s" => Ok(PacketValue::String(value.to_owned())),
+        // G-Earth uses b for both, the value tells them apart
+        "b" => match value {
+            "true" => Ok(PacketValue::Bool(true)),
+            "false" => Ok(PacketValue::Bool(false)),
+            _ => value.parse().map(PacketValue::Byte).map_err(|_| invalid()),
+        },
+        _ => Err(ExpressionError::UnknownType(kind.to_owned())),
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    position: usize,
+}
+
+impl Parser {
+    fn new(input: &str) -> Self {
+        Parser {
+            chars: input.chars().collect(),
+            position: 0,
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self
+            .chars
+            .get(self.position)
+            .is_some_and(|c| c.is_whitespace())
+        {
+            self.position += 1;
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), ExpressionError> {
+        match self.chars.get(self.position) {
+            Some(c) if *c == expected => {
+                self.position += 1;
+                Ok(())
+            }
+            Some(c) => Err(ExpressionError::UnexpectedChar {
+                found: *c,
+                position: self.position,
+            }),
+            None => Err(ExpressionError::UnexpectedEnd),
+        }
+    }
+
+    // Reads one {kind:value} token, or None at the end of the input.
+    fn token(&mut self) -> Result<Option<(String, String)>, ExpressionError> {
+        self.skip_whitespace();
+        if self.position >= self.chars.len() {
+            return Ok(None);
+        }
+        self.expect('{')?;
+
+        let mut kind = String::new();
+        loop {
+            match self.chars.get(self.position) {
+                Some(':') => break,
+                Some(c) => kind.push(*c),
+                None => return Err(ExpressionError::UnexpectedEnd),
+            }
+            self.position += 1;
+        }
+        self.expect(':')?;
+
+        let value = if self.chars.get(self.position) == Some(&'"') {
+            self.quoted()?
+        } else {
+            let mut value = String::new();
+            loop {
+                match self.chars.get(self.position) {
+                    Some('}') => break,
+                    Some(c) => value.push(*c),
+                    None => return Err(ExpressionError::UnexpectedEnd),
+                }
+                self.position += 1;
+            }
+            value.trim().to_owned()
+        };
+        self.expect('}')?;
+
+        Ok(Some((kind.trim().to_owned(), value)))
+    }
+
+    fn quoted(&mut self) -> Result<String, ExpressionError> {
+        self.expect('"')?;
+        let mut value = String::new();
+        loop {
+            match self.chars.get(self.position) {
+                Some('"') => break,
+                Some('\\') => {
+                    self.position += 1;
+                    match self.chars.get(self.position) {
+                        Some(c) => value.push(*c),
+                        None => return Err(ExpressionError::UnexpectedEnd),
+                    }
+                }
+                Some(c) => value.push(*c),
+                None => return Err(ExpressionError::UnexpectedEnd),
+            }
+            self.position += 1;
+        }
+        self.expect('"')?;
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_and_prints_structured_expressions() {
+        let input = r#"{out:Chat}{s:"say \"hi\" \\o/"}{i:-1}{b:false}{b:7}{u:40000}{l:9000000000}"#;
+        let expression = Expression::parse(input).unwrap();
+
+        assert_eq!(expression.direction, Some(Direction::Out));
+        assert_eq!(expression.header, HeaderRef::Name("Chat".to_owned()));
+        assert_eq!(
+            expression.values,
+            vec![
+                PacketValue::String("say \"hi\" \\o/".to_owned()),
+                PacketValue::Int(-1),
+                PacketValue::Bool(false),
+                PacketValue::Byte(7),
+                PacketValue::Short(40000),
+                PacketValue::Long(9000000000),
+            ]
+        );
+        assert_eq!(expression.to_string(), input);
+    }
+
+    #[test]
+    fn numeric_headers_build_without_definitions() {
+        let expression = Expression::parse("{in:1066} {s:\"hello\"} {i:0}").unwrap();
+        let packet = expression.to_packet(Direction::Out).unwrap();
+
+        assert_eq!(packet.direction, Direction::In);
+        assert_eq!(packet.get_header().unwrap(), 1066);
+        assert_eq!(
+            escape(&packet.bytes),
+            "[0][0][0][13][4]*[0][5]hello[0][0][0][0]"
+        );
+    }
+
+    #[test]
+    fn escaped_form_round_trips() {
+        let packet = PacketBuilder::new(1066, Direction::In)
+            .append_string("[hi]{}")
+            .append_int(200)
+            .build();
+        let escaped = packet.to_string();
+
+        assert_eq!(unescape(&escaped).unwrap(), packet.bytes);
+        let parsed = parse_packet(&escaped, Direction::In).unwrap();
+        assert_eq!(parsed.bytes, packet.bytes);
+    }
+
+    #[test]
+    fn escaped_form_is_latin_1() {
+        assert_eq!(unescape("a\u{e9}[233]").unwrap(), b"a\xe9\xe9");
+        assert_eq!(escape(b"a\xe9"), "a\u{e9}");
+        assert_eq!(
+            unescape("[0]\u{263a}"),
+            Err(ExpressionError::UnexpectedChar {
+                found: '\u{263a}',
+                position: 3
+            })
+        );
+        assert!(Expression::parse("{out:1}{u:65536}").is_err());
+    }
+
+    #[test]
+    fn escaped_form_fixes_the_length_prefix() {
+        let parsed = parse_packet("[0][0][0][0][4]*abc", Direction::In).unwrap();
+
+        assert_eq!(parsed.read_length().unwrap(), 5);
+    }
+
+    #[test]
+    fn reports_bad_expressions() {
+        assert_eq!(
+            Expression::parse("{s:\"x\"}"),
+            Err(ExpressionError::MissingHeader)
+        );
+        assert_eq!(
+            Expression::parse("{out:Chat}{x:1}"),
+            Err(ExpressionError::UnknownType("x".to_owned()))
+        );
+        assert!(matches!(
+            Expression::parse("{out:Chat}{i:abc}"),
+            Err(ExpressionError::InvalidValue { .. })
+        ));
+        assert_eq!(
+            Expression::parse("{out:Chat}{s:\"open"),
+            Err(ExpressionError::UnexpectedEnd)
+        );
+    }
+}
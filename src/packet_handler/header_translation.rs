@@ -0,0 +1,254 @@
+use crate::packet_handler::direction::Direction;
+use crate::packet_handler::expression::{self, Expression, ExpressionError, HeaderRef};
+use crate::packet_handler::message_registry::MessageRegistry;
+use crate::packet_handler::packet::Packet;
+use crate::packet_handler::packet_error::PacketError;
+use crate::packet_handler::recent::RecentPacket;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TranslationError {
+    // the old release has no name for this header, so there's nothing to look up
+    Unknown { direction: Direction, header: u16 },
+    // the message is gone in the new release
+    Removed { direction: Direction, name: String },
+    // {h:..} doesn't say which way the packet goes and ids overlap between directions
+    NoDirection(u16),
+    Expression(ExpressionError),
+    Packet(PacketError),
+}
+
+impl fmt::Display for TranslationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TranslationError::Unknown { direction, header } => {
+                write!(
+                    f,
+                    "{} header {} is unknown in the old release",
+                    direction, header
+                )
+            }
+            TranslationError::Removed { direction, name } => {
+                write!(f, "{} message {} no longer exists", direction, name)
+            }
+            TranslationError::NoDirection(header) => {
+                write!(
+                    f,
+                    "header {} has no direction, write it as {{in:..}} or {{out:..}}",
+                    header
+                )
+            }
+            TranslationError::Expression(e) => write!(f, "{}", e),
+            TranslationError::Packet(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for TranslationError {}
+
+impl From<ExpressionError> for TranslationError {
+    fn from(e: ExpressionError) -> Self {
+        TranslationError::Expression(e)
+    }
+}
+
+impl From<PacketError> for TranslationError {
+    fn from(e: PacketError) -> Self {
+        TranslationError::Packet(e)
+    }
+}
+
+// Moves header ids saved against one release over to another by way of the message names.
+pub struct HeaderTranslator<'a> {
+    old: &'a MessageRegistry,
+    new: &'a MessageRegistry,
+}
+
+impl<'a> HeaderTranslator<'a> {
+    pub fn new(old: &'a MessageRegistry, new: &'a MessageRegistry) -> Self {
+        HeaderTranslator { old, new }
+    }
+
+    pub fn translate(&self, direction: Direction, header: u16) -> Result<u16, TranslationError> {
+        let name = &self
+            .old
+            .get(direction, header)
+            .ok_or(TranslationError::Unknown { direction, header })?
+            .name;
+        self.new
+            .find(direction, name)
+            .map(|message| message.header)
+            .ok_or_else(|| TranslationError::Removed {
+                direction,
+                name: name.clone(),
+            })
+    }
+
+    // A captured frame with its header rewritten, body untouched.
+    pub fn translate_packet(&self, packet: &Packet) -> Result<Packet, TranslationError> {
+        let header = self.translate(packet.direction, packet.get_header()?)?;
+        let mut bytes = packet.bytes.clone();
+        bytes[4..6].copy_from_slice(&header.to_be_bytes());
+
+        let name = self
+            .new
+            .get(packet.direction, header)
+            .map(|message| message.name.clone());
+        Ok(Packet::new(
+            Some(bytes),
+            name,
+            Some(header),
+            packet.direction,
+        ))
+    }
+
+    // Named headers are left alone, they already survive an update.
+    pub fn translate_expression(
+        &self,
+        expression: &Expression,
+    ) -> Result<Expression, TranslationError> {
+        let HeaderRef::Id(header) = expression.header else {
+            return Ok(expression.clone());
+        };
+        let direction = expression
+            .direction
+            .ok_or(TranslationError::NoDirection(header))?;
+
+        Ok(Expression {
+            header: HeaderRef::Id(self.translate(direction, header)?),
+            ..expression.clone()
+        })
+    }
+
+    // One saved line in either G-Earth form, or copied from the console's `recent`. Escaped frames
+    // carry no direction of their own.
+    pub fn translate_line(
+        &self,
+        line: &str,
+        direction: Direction,
+    ) -> Result<String, TranslationError> {
+        let line = line.trim();
+        if let Some(recent) = RecentPacket::parse(line) {
+            let packet = Packet::new(Some(recent.bytes), None, None, recent.direction);
+            let translated = self.translate_packet(&packet)?;
+            return Ok(RecentPacket::new(&translated, recent.injected).to_string());
+        }
+        if line.starts_with('{') {
+            let mut parsed = Expression::parse(line)?;
+            if parsed.direction.is_none() {
+                parsed.direction = Some(direction);
+            }
+            return Ok(self.translate_expression(&parsed)?.to_string());
+        }
+
+        let packet = expression::parse_packet(line, direction)?;
+        Ok(expression::escape(&self.translate_packet(&packet)?.bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet_handler::message_registry::MessageInfo;
+    use crate::packet_handler::packet_builder::PacketBuilder;
+
+    fn registries() -> (MessageRegistry, MessageRegistry) {
+        let mut old = MessageRegistry::new();
+        old.insert(MessageInfo::new(Direction::Out, 1066, "Chat"));
+        old.insert(MessageInfo::new(Direction::In, 1066, "Users"));
+        old.insert(MessageInfo::new(Direction::Out, 7, "Gone"));
+        let mut new = MessageRegistry::new();
+        new.insert(MessageInfo::new(Direction::Out, 2001, "Chat"));
+        new.insert(MessageInfo::new(Direction::In, 3002, "Users"));
+        (old, new)
+    }
+
+    #[test]
+    fn remaps_packets_by_name() {
+        let (old, new) = registries();
+        let translator = HeaderTranslator::new(&old, &new);
+        let packet = PacketBuilder::new(1066, Direction::Out)
+            .append_string("hi")
+            .build();
+
+        let translated = translator.translate_packet(&packet).unwrap();
+
+        assert_eq!(translated.get_header().unwrap(), 2001);
+        assert_eq!(translated.name.as_deref(), Some("Chat"));
+        assert_eq!(translated.get_body().unwrap(), packet.get_body().unwrap());
+        assert_eq!(translator.translate(Direction::In, 1066), Ok(3002));
+    }
+
+    #[test]
+    fn remaps_saved_expressions() {
+        let (old, new) = registries();
+        let translator = HeaderTranslator::new(&old, &new);
+
+        assert_eq!(
+            translator
+                .translate_line("{out:1066}{s:\"hi\"}", Direction::In)
+                .unwrap(),
+            "{out:2001}{s:\"hi\"}"
+        );
+        assert_eq!(
+            translator
+                .translate_line("{h:1066}", Direction::In)
+                .unwrap(),
+            "{in:3002}"
+        );
+        assert_eq!(
+            translator
+                .translate_line("{out:Chat}", Direction::In)
+                .unwrap(),
+            "{out:Chat}"
+        );
+        assert_eq!(
+            translator
+                .translate_line("[0][0][0][2][4]*", Direction::Out)
+                .unwrap(),
+            "[0][0][0][2][7]Ñ"
+        );
+    }
+
+    #[test]
+    fn remaps_lines_from_recent() {
+        let (old, new) = registries();
+        let translator = HeaderTranslator::new(&old, &new);
+
+        assert_eq!(
+            translator
+                .translate_line("[In ] [1066] Users [0][0][0][2][4]*", Direction::Out)
+                .unwrap(),
+            "[In ] [3002] Users [0][0][0][2][11]º"
+        );
+        assert_eq!(
+            translator
+                .translate_line(
+                    "[Out] [1066] Chat (injected) [0][0][0][2][4]*",
+                    Direction::In
+                )
+                .unwrap(),
+            "[Out] [2001] Chat (injected) [0][0][0][2][7]Ñ"
+        );
+    }
+
+    #[test]
+    fn reports_what_cannot_be_moved() {
+        let (old, new) = registries();
+        let translator = HeaderTranslator::new(&old, &new);
+
+        assert!(matches!(
+            translator.translate(Direction::Out, 7),
+            Err(TranslationError::Removed { .. })
+        ));
+        assert!(matches!(
+            translator.translate(Direction::Out, 8),
+            Err(TranslationError::Unknown { .. })
+        ));
+        let unaddressed = Expression::parse("{h:1066}").unwrap();
+        assert_eq!(
+            translator.translate_expression(&unaddressed),
+            Err(TranslationError::NoDirection(1066))
+        );
+    }
+}
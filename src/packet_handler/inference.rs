@@ -0,0 +1,462 @@
+use crate::packet_handler::direction::Direction;
+use crate::packet_handler::packet::{Packet, BODY_OFFSET};
+use crate::packet_handler::structure::Structure;
+use std::collections::HashMap;
+
+// Guesses a structure signature for a body nobody documented. Every offset can be read as a few
+// different things (a u16-prefixed string, an int, a bool, a short or a plain byte), so we score
+// each reading by how believable it is and keep the best scoring way through the whole body.
+
+const MAX_GROUP_COUNT: i32 = 100;
+const MAX_GROUP_WIDTH: usize = 8;
+const SAMPLES_PER_HEADER: usize = 20;
+const MAX_PROPOSALS: usize = 8;
+const REGUESS_EVERY: usize = 5;
+// Bigger bodies aren't guessed at: the search is linear in the body but allocates for every
+// byte, and long bodies are rarely simple enough to guess anyway. Also bounds what we keep.
+const MAX_BODY: usize = 512;
+// Headers followed at once, the one seen longest ago makes room for a new one.
+const MAX_HEADERS: usize = 1024;
+// Reguesses in a row that have to agree before a header stops being sampled.
+const SETTLE_AFTER: usize = 3;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StructureGuess {
+    pub signature: String,
+    // 0.0 to 1.0, roughly the share of the body we're sure about
+    pub confidence: f32,
+    pub samples: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Token {
+    field: char,
+    length: usize,
+    score: f32,
+    confidence: f32,
+    // the int's value, used to spot counted groups
+    value: Option<i32>,
+}
+
+pub fn infer(packet: &Packet) -> StructureGuess {
+    let body = packet.get_body().unwrap_or_default();
+    let tokens = best_tokens(&body);
+
+    let confidence = if body.is_empty() {
+        1.0
+    } else {
+        tokens
+            .iter()
+            .map(|token| token.confidence * token.length as f32)
+            .sum::<f32>()
+            / body.len() as f32
+    };
+
+    StructureGuess {
+        signature: fold_groups(&tokens),
+        confidence,
+        samples: 1,
+    }
+}
+
+fn candidates(body: &[u8], position: usize) -> Vec<Token> {
+    let rest = &body[position..];
+    let mut tokens = Vec::new();
+
+    if rest.len() >= 2 {
+        let length = u16::from_be_bytes([rest[0], rest[1]]) as usize;
+        if let Some(Ok(text)) = rest
+            .get(2..2 + length)
+            .map(|bytes| std::str::from_utf8(bytes))
+        {
+            let printable = text
+                .chars()
+                .all(|c| !c.is_control() || c == '\n' || c == '\r' || c == '\t');
+            if length == 0 {
+                tokens.push(token('s', 2, 0.5, 0.4, None));
+            } else if printable {
+                tokens.push(token(
+                    's',
+                    2 + length,
+                    3.0 + length.min(10) as f32 * 0.3,
+                    0.95,
+                    None,
+                ));
+            }
+        }
+    }
+
+    if rest.len() >= 4 {
+        let value = i32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]);
+        let (score, confidence) = match value {
+            0..=1_000_000 => (2.0, 0.8),
+            -1000..=-1 => (1.5, 0.6),
+            _ => (0.2, 0.3),
+        };
+        tokens.push(token('i', 4, score, confidence, Some(value)));
+    }
+
+    if rest.len() >= 2 {
+        tokens.push(token('u', 2, 0.3, 0.3, None));
+    }
+
+    if rest[0] <= 1 {
+        tokens.push(token('B', 1, 0.4, 0.5, None));
+    }
+
+    // a plain byte always fits, so there's always some way through the body
+    tokens.push(token('b', 1, -0.5, 0.1, None));
+    tokens
+}
+
+fn token(field: char, length: usize, score: f32, confidence: f32, value: Option<i32>) -> Token {
+    Token {
+        field,
+        length,
+        score,
+        confidence,
+        value,
+    }
+}
+
+// Dynamic programming from the end of the body: best[p] is the best score for reading body[p..].
+fn best_tokens(body: &[u8]) -> Vec<Token> {
+    let mut best: Vec<Option<(f32, Token)>> = vec![None; body.len() + 1];
+    let mut totals = vec![0.0f32; body.len() + 1];
+
+    for position in (0..body.len()).rev() {
+        for candidate in candidates(body, position) {
+            let total = candidate.score + totals[position + candidate.length];
+            if best[position].is_none_or(|(score, _)| total > score) {
+                best[position] = Some((total, candidate));
+            }
+        }
+        totals[position] = best[position].map(|(score, _)| score).unwrap_or_default();
+    }
+
+    let mut tokens = Vec::new();
+    let mut position = 0;
+    while let Some((_, token)) = best.get(position).copied().flatten() {
+        tokens.push(token);
+        position += token.length;
+    }
+    tokens
+}
+
+// Turns an int followed by that many copies of the same run of fields into a counted group,
+// e.g. i s i s i s with the int being 3 becomes {s}. Needs at least two copies to be believable.
+fn fold_groups(tokens: &[Token]) -> String {
+    let mut signature = String::new();
+    let mut index = 0;
+
+    'outer: while index < tokens.len() {
+        let token = tokens[index];
+        if let Some(count @ 2..=MAX_GROUP_COUNT) = token.value {
+            let count = count as usize;
+            for width in 1..=MAX_GROUP_WIDTH {
+                let end = index + 1 + count * width;
+                if end > tokens.len() {
+                    break;
+                }
+                let group = &tokens[index + 1..index + 1 + width];
+                let repeats = tokens[index + 1..end]
+                    .chunks(width)
+                    .all(|chunk| chunk.iter().zip(group).all(|(a, b)| a.field == b.field));
+                if repeats {
+                    signature.push('{');
+                    signature.push_str(&fold_groups(group));
+                    signature.push('}');
+                    index = end;
+                    continue 'outer;
+                }
+            }
+        }
+
+        signature.push(token.field);
+        index += 1;
+    }
+
+    signature
+}
+
+// Collects guesses for the same header over many packets. One packet can easily fool the
+// heuristics, but a signature that decodes every sample we've seen is a lot more believable.
+#[derive(Debug, Clone, Default)]
+pub struct StructureLearner {
+    headers: HashMap<u16, HeaderSamples>,
+    // bumped on every learn, for finding the header seen longest ago
+    clock: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+struct HeaderSamples {
+    // whole frames, none longer than MAX_BODY plus the prefix and header
+    frames: Vec<Vec<u8>>,
+    // signature -> (times proposed, summed confidence)
+    proposals: HashMap<String, (usize, f32)>,
+    seen: usize,
+    last_seen: u64,
+    // what learn last came up with, only worked out again every REGUESS_EVERY samples
+    guess: Option<StructureGuess>,
+    // how many reguesses in a row picked the same signature
+    agreed: usize,
+    // done sampling, only the guess is kept
+    settled: bool,
+}
+
+impl StructureLearner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn learn(&mut self, packet: &Packet) -> Option<StructureGuess> {
+        let header = packet.get_header().ok()?;
+        self.clock += 1;
+        if !self.headers.contains_key(&header) && self.headers.len() >= MAX_HEADERS {
+            let oldest = self
+                .headers
+                .iter()
+                .min_by_key(|(_, samples)| samples.last_seen)
+                .map(|(header, _)| *header);
+            if let Some(oldest) = oldest {
+                self.headers.remove(&oldest);
+            }
+        }
+        let samples = self.headers.entry(header).or_default();
+        samples.seen += 1;
+        samples.last_seen = self.clock;
+
+        let sampled = !samples.settled && packet.bytes.len() <= BODY_OFFSET + MAX_BODY;
+        if sampled {
+            if samples.frames.len() == SAMPLES_PER_HEADER {
+                samples.frames.remove(0);
+            }
+            samples.frames.push(packet.bytes.clone());
+            samples.propose(infer(packet));
+        }
+
+        if sampled && (samples.seen == 1 || samples.seen.is_multiple_of(REGUESS_EVERY)) {
+            samples.reguess();
+        }
+        samples.guess.clone().map(|guess| StructureGuess {
+            samples: samples.seen,
+            ..guess
+        })
+    }
+
+    // Picks the proposed signature that cleanly decodes the most of the kept samples.
+    pub fn guess(&self, header: u16) -> Option<StructureGuess> {
+        let samples = self.headers.get(&header)?;
+        if samples.settled {
+            return samples.guess.clone();
+        }
+        best(samples.score())
+    }
+
+    // Once a message has a real structure there's nothing left to learn about it.
+    pub fn forget(&mut self, header: u16) {
+        self.headers.remove(&header);
+    }
+}
+
+impl HeaderSamples {
+    fn reguess(&mut self) {
+        let scored = self.score();
+        // nothing we kept decodes with these, so they're not coming back
+        self.proposals.retain(|signature, _| {
+            scored
+                .iter()
+                .any(|guess| &guess.signature == signature && guess.confidence > 0.0)
+        });
+
+        let guess = best(scored);
+        let same = guess.as_ref().map(|guess| &guess.signature)
+            == self.guess.as_ref().map(|guess| &guess.signature);
+        self.agreed = if same && guess.is_some() {
+            self.agreed + 1
+        } else {
+            0
+        };
+        self.guess = guess;
+
+        if self.agreed >= SETTLE_AFTER {
+            self.settled = true;
+            self.frames = Vec::new();
+            self.proposals = HashMap::new();
+        }
+    }
+
+    // When full, the signature proposed the least makes room.
+    fn propose(&mut self, guess: StructureGuess) {
+        if !self.proposals.contains_key(&guess.signature) && self.proposals.len() >= MAX_PROPOSALS {
+            let weakest = self
+                .proposals
+                .iter()
+                .min_by(|(_, a), (_, b)| a.0.cmp(&b.0).then_with(|| a.1.total_cmp(&b.1)))
+                .map(|(signature, _)| signature.clone());
+            if let Some(weakest) = weakest {
+                self.proposals.remove(&weakest);
+            }
+        }
+
+        let proposal = self.proposals.entry(guess.signature).or_default();
+        proposal.0 += 1;
+        proposal.1 += guess.confidence;
+    }
+
+    fn score(&self) -> Vec<StructureGuess> {
+        self.proposals
+            .iter()
+            .filter_map(|(signature, (proposed, summed_confidence))| {
+                let structure = Structure::parse(signature).ok()?;
+                let decoded = self
+                    .frames
+                    .iter()
+                    .filter(|frame| {
+                        let mut packet =
+                            Packet::new(Some(frame.to_vec()), None, None, Direction::In);
+                        structure.fits(&mut packet)
+                    })
+                    .count();
+                let fit = decoded as f32 / self.frames.len() as f32;
+                let average = summed_confidence / *proposed as f32;
+                Some(StructureGuess {
+                    signature: signature.clone(),
+                    confidence: fit * average,
+                    samples: self.seen,
+                })
+            })
+            .collect()
+    }
+}
+
+fn best(guesses: Vec<StructureGuess>) -> Option<StructureGuess> {
+    guesses.into_iter().max_by(|a, b| {
+        a.confidence
+            .total_cmp(&b.confidence)
+            .then_with(|| b.signature.cmp(&a.signature))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet_handler::packet_builder::PacketBuilder;
+
+    #[test]
+    fn finds_strings_ints_and_bools() {
+        let packet = PacketBuilder::new(1, Direction::In)
+            .append_int(42)
+            .append_string("hello world")
+            .append_bool(true)
+            .append_int(0)
+            .build();
+
+        let guess = infer(&packet);
+        assert_eq!(guess.signature, "isBi");
+        assert!(guess.confidence > 0.7, "{:?}", guess);
+    }
+
+    #[test]
+    fn folds_counted_arrays() {
+        let packet = PacketBuilder::new(1, Direction::In)
+            .append_int(3)
+            .append_string("alpha")
+            .append_int(1)
+            .append_string("beta")
+            .append_int(2)
+            .append_string("gamma")
+            .append_int(3)
+            .build();
+
+        assert_eq!(infer(&packet).signature, "{si}");
+    }
+
+    #[test]
+    fn noise_gets_low_confidence() {
+        let packet = PacketBuilder::new(1, Direction::In)
+            .append_bytes(&[0xf3, 0x81, 0xa7, 0xee, 0x90, 0x19, 0xcc])
+            .build();
+
+        assert!(infer(&packet).confidence < 0.4);
+    }
+
+    #[test]
+    fn learner_prefers_the_signature_that_fits_every_sample() {
+        let samples = [
+            ("", 5, true),
+            ("bob", 0, false),
+            ("x", 1, true),
+            ("alice", 1_700_000_000, false),
+        ];
+        let mut learner = StructureLearner::new();
+
+        // on its own the first sample reads as BBiB
+        let first = PacketBuilder::new(77, Direction::In)
+            .append_string("")
+            .append_int(5)
+            .append_bool(true)
+            .build();
+        assert_ne!(infer(&first).signature, "siB");
+
+        for (name, number, flag) in samples {
+            let packet = PacketBuilder::new(77, Direction::In)
+                .append_string(name)
+                .append_int(number)
+                .append_bool(flag)
+                .build();
+            learner.learn(&packet);
+        }
+
+        let guess = learner.guess(77).unwrap();
+        assert_eq!(guess.signature, "siB");
+        assert_eq!(guess.samples, 4);
+        assert!(learner.guess(78).is_none());
+    }
+
+    #[test]
+    fn learner_keeps_a_bounded_set_of_proposals() {
+        let mut learner = StructureLearner::new();
+        for length in 0..50 {
+            let packet = PacketBuilder::new(9, Direction::In)
+                .append_bytes(&vec![0xf3; length])
+                .build();
+            learner.learn(&packet);
+        }
+
+        assert!(learner.headers[&9].proposals.len() <= MAX_PROPOSALS);
+        learner.forget(9);
+        assert!(learner.guess(9).is_none());
+    }
+
+    #[test]
+    fn learner_memory_stays_bounded() {
+        let mut learner = StructureLearner::new();
+        let chat = |header: u16, text: &str| {
+            PacketBuilder::new(header, Direction::In)
+                .append_string(text)
+                .append_int(1)
+                .build()
+        };
+
+        // a steady signature settles and its samples are let go
+        for n in 0..SETTLE_AFTER * REGUESS_EVERY {
+            learner.learn(&chat(1, &format!("hello {}", n)));
+        }
+        assert!(learner.headers[&1].settled);
+        assert!(learner.headers[&1].frames.is_empty());
+        assert_eq!(learner.guess(1).unwrap().signature, "si");
+
+        // too big to sample
+        let big = PacketBuilder::new(2, Direction::In)
+            .append_bytes(&[0; MAX_BODY + 1])
+            .build();
+        assert!(learner.learn(&big).is_none());
+        assert!(learner.headers[&2].frames.is_empty());
+
+        for header in 0..(MAX_HEADERS + 10) as u16 {
+            learner.learn(&chat(header, "x"));
+        }
+        assert_eq!(learner.headers.len(), MAX_HEADERS);
+    }
+}
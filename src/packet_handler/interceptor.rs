@@ -0,0 +1,169 @@
+use crate::packet_handler::packet::Packet;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// What an interceptor wants done with a frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Forward,
+    Drop,
+    // send these instead, they go through the rest of the chain like the original would have
+    Replace(Vec<Packet>),
+    // forward after waiting. Everything behind it in the same direction waits too, so order is kept.
+    Delay(Duration),
+}
+
+// Sees every complete frame before it's written, in both directions. Packets arrive named
+// when the definitions know them.
+pub trait PacketInterceptor: Send {
+    // Shown in the log when the interceptor changes something.
+    fn name(&self) -> String;
+
+    fn intercept(&mut self, packet: &Packet) -> Verdict;
+}
+
+// What's left of a frame after the whole chain had its say.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Outcome {
+    pub packets: Vec<Packet>,
+    pub delay: Duration,
+    // the last interceptor that dropped or replaced something, for the log
+    pub changed_by: Option<String>,
+}
+
+// Ordered interceptors shared by both directions of a session.
+#[derive(Clone, Default)]
+pub struct InterceptorChain {
+    interceptors: Arc<Mutex<Vec<Box<dyn PacketInterceptor>>>>,
+}
+
+impl InterceptorChain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Runs after everything added before it.
+    pub fn add<I: PacketInterceptor + 'static>(&self, interceptor: I) {
+        self.interceptors
+            .lock()
+            .unwrap()
+            .push(Box::new(interceptor));
+    }
+
+    pub fn len(&self) -> usize {
+        self.interceptors.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn run(&self, packet: Packet) -> Outcome {
+        let mut outcome = Outcome {
+            packets: vec![packet],
+            delay: Duration::ZERO,
+            changed_by: None,
+        };
+
+        for interceptor in self.interceptors.lock().unwrap().iter_mut() {
+            let mut next = Vec::with_capacity(outcome.packets.len());
+            for packet in outcome.packets {
+                match interceptor.intercept(&packet) {
+                    Verdict::Forward => next.push(packet),
+                    Verdict::Drop => outcome.changed_by = Some(interceptor.name()),
+                    Verdict::Replace(packets) => {
+                        outcome.changed_by = Some(interceptor.name());
+                        next.extend(packets);
+                    }
+                    Verdict::Delay(delay) => {
+                        outcome.delay += delay;
+                        next.push(packet);
+                    }
+                }
+            }
+            outcome.packets = next;
+            if outcome.packets.is_empty() {
+                break;
+            }
+        }
+
+        outcome
+    }
+}
+
+impl fmt::Debug for InterceptorChain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<String> = self
+            .interceptors
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|interceptor| interceptor.name())
+            .collect();
+        f.debug_tuple("InterceptorChain").field(&names).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet_handler::direction::Direction;
+    use crate::packet_handler::packet_builder::PacketBuilder;
+
+    struct Fixed(fn(&Packet) -> Verdict);
+
+    impl PacketInterceptor for Fixed {
+        fn name(&self) -> String {
+            String::from("fixed")
+        }
+
+        fn intercept(&mut self, packet: &Packet) -> Verdict {
+            (self.0)(packet)
+        }
+    }
+
+    fn packet(header: u16) -> Packet {
+        PacketBuilder::new(header, Direction::Out).build()
+    }
+
+    fn headers(outcome: &Outcome) -> Vec<u16> {
+        outcome
+            .packets
+            .iter()
+            .map(|packet| packet.get_header().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn an_empty_chain_forwards() {
+        let outcome = InterceptorChain::new().run(packet(1));
+        assert_eq!(headers(&outcome), vec![1]);
+        assert_eq!(outcome.changed_by, None);
+    }
+
+    #[test]
+    fn replacements_go_through_the_rest_of_the_chain() {
+        let chain = InterceptorChain::new();
+        chain.add(Fixed(|_| Verdict::Replace(vec![packet(2), packet(3)])));
+        chain.add(Fixed(|packet| match packet.get_header().unwrap() {
+            2 => Verdict::Drop,
+            _ => Verdict::Delay(Duration::from_millis(5)),
+        }));
+
+        let outcome = chain.run(packet(1));
+
+        assert_eq!(headers(&outcome), vec![3]);
+        assert_eq!(outcome.delay, Duration::from_millis(5));
+        assert_eq!(outcome.changed_by.as_deref(), Some("fixed"));
+    }
+
+    #[test]
+    fn dropping_stops_the_chain() {
+        let chain = InterceptorChain::new();
+        chain.add(Fixed(|_| Verdict::Drop));
+        chain.add(Fixed(|_| panic!("should not run")));
+
+        assert!(chain.run(packet(1)).packets.is_empty());
+    }
+}
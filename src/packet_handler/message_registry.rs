@@ -0,0 +1,173 @@
+use crate::packet_handler::direction::Direction;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+lazy_static::lazy_static! {
+    static ref CURRENT_REGISTRY: RwLock<Arc<MessageRegistry>> =
+        RwLock::new(Arc::new(MessageRegistry::default()));
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessageInfo {
+    pub direction: Direction,
+    pub header: u16,
+    pub name: String,
+    pub hash: Option<String>,
+    // structure signature, when a source knows one
+    pub structure: Option<String>,
+    // other names the message can be looked up by
+    pub aliases: Vec<String>,
+    // still named, just left out of the packet log
+    pub hidden: bool,
+}
+
+impl MessageInfo {
+    pub fn new(direction: Direction, header: u16, name: &str) -> Self {
+        MessageInfo {
+            direction,
+            header,
+            name: name.to_owned(),
+            hash: None,
+            structure: None,
+            aliases: Vec::new(),
+            hidden: false,
+        }
+    }
+}
+
+// Every known message for one client release. Incoming and outgoing ids overlap all the time,
+// so everything is keyed by direction as well.
+#[derive(Debug, Clone, Default)]
+pub struct MessageRegistry {
+    by_header: HashMap<(Direction, u16), MessageInfo>,
+    by_name: HashMap<(Direction, String), u16>,
+    // the client release these definitions belong to, when we know it
+    pub release: Option<String>,
+}
+
+impl MessageRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Replaces whatever was registered under the same header or name before.
+    // Aliases never push another message out, one that's already taken is simply not registered.
+    pub fn insert(&mut self, message: MessageInfo) {
+        let direction = message.direction;
+        self.remove(direction, message.header);
+        if let Some(previous) = self
+            .by_name
+            .get(&(direction, message.name.clone()))
+            .copied()
+        {
+            self.remove(direction, previous);
+        }
+
+        self.by_name
+            .insert((direction, message.name.clone()), message.header);
+        for alias in &message.aliases {
+            self.by_name
+                .entry((direction, alias.clone()))
+                .or_insert(message.header);
+        }
+        self.by_header.insert((direction, message.header), message);
+    }
+
+    pub fn remove(&mut self, direction: Direction, header: u16) -> Option<MessageInfo> {
+        let message = self.by_header.remove(&(direction, header))?;
+        for name in std::iter::once(&message.name).chain(&message.aliases) {
+            if self.by_name.get(&(direction, name.clone())) == Some(&header) {
+                self.by_name.remove(&(direction, name.clone()));
+            }
+        }
+        Some(message)
+    }
+
+    pub fn get(&self, direction: Direction, header: u16) -> Option<&MessageInfo> {
+        self.by_header.get(&(direction, header))
+    }
+
+    pub fn find(&self, direction: Direction, name: &str) -> Option<&MessageInfo> {
+        let header = self.by_name.get(&(direction, name.to_owned()))?;
+        self.get(direction, *header)
+    }
+
+    pub fn messages(&self) -> impl Iterator<Item = &MessageInfo> {
+        self.by_header.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_header.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_header.is_empty()
+    }
+
+    // The registry the proxy names packets with. Readers get a cheap snapshot and never wait
+    // on a reload, installing a new registry just swaps the pointer.
+    pub fn current() -> Arc<MessageRegistry> {
+        CURRENT_REGISTRY.read().unwrap().clone()
+    }
+
+    pub fn install(registry: MessageRegistry) {
+        *CURRENT_REGISTRY.write().unwrap() = Arc::new(registry);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(direction: Direction, header: u16, name: &str) -> MessageInfo {
+        MessageInfo::new(direction, header, name)
+    }
+
+    #[test]
+    fn colliding_ids_stay_apart_by_direction() {
+        let mut registry = MessageRegistry::new();
+        registry.insert(message(Direction::In, 1066, "Chat"));
+        registry.insert(message(Direction::Out, 1066, "MoveAvatar"));
+
+        assert_eq!(registry.get(Direction::In, 1066).unwrap().name, "Chat");
+        assert_eq!(
+            registry.get(Direction::Out, 1066).unwrap().name,
+            "MoveAvatar"
+        );
+        assert_eq!(
+            registry.find(Direction::Out, "MoveAvatar").unwrap().header,
+            1066
+        );
+        assert!(registry.find(Direction::Out, "Chat").is_none());
+        assert_eq!(registry.len(), 2);
+    }
+
+    #[test]
+    fn reinserting_replaces_stale_entries() {
+        let mut registry = MessageRegistry::new();
+        registry.insert(message(Direction::In, 1, "Old"));
+        registry.insert(message(Direction::In, 1, "New"));
+        registry.insert(message(Direction::In, 2, "New"));
+
+        assert!(registry.find(Direction::In, "Old").is_none());
+        assert!(registry.get(Direction::In, 1).is_none());
+        assert_eq!(registry.find(Direction::In, "New").unwrap().header, 2);
+        assert_eq!(registry.len(), 1);
+    }
+
+    #[test]
+    fn aliases_resolve_without_displacing_others() {
+        let mut registry = MessageRegistry::new();
+        registry.insert(message(Direction::Out, 2, "Shout"));
+        let mut chat = message(Direction::Out, 1, "Chat");
+        chat.aliases = vec!["Talk".to_owned(), "Shout".to_owned()];
+        registry.insert(chat);
+
+        assert_eq!(registry.find(Direction::Out, "Talk").unwrap().header, 1);
+        assert_eq!(registry.find(Direction::Out, "Shout").unwrap().header, 2);
+
+        registry.remove(Direction::Out, 1);
+        assert!(registry.find(Direction::Out, "Talk").is_none());
+        assert_eq!(registry.find(Direction::Out, "Shout").unwrap().header, 2);
+    }
+}
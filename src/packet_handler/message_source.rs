@@ -0,0 +1,294 @@
+use crate::logger::ConsoleLogger;
+use crate::packet_handler::definition_cache::DefinitionCache;
+use crate::packet_handler::definitions::{self, DefinitionError};
+use crate::packet_handler::direction::Direction;
+use crate::packet_handler::message_registry::MessageInfo;
+use serde_json::Value;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+const SULEK_URL: &str = "https://api.sulek.dev/releases";
+
+pub type LoadFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<Vec<MessageInfo>, DefinitionError>> + Send + 'a>>;
+
+// Somewhere message definitions come from. The proxy asks each configured source in turn
+// and merges what they return, see `definitions::merge`.
+pub trait MessageSource: Send + Sync {
+    // Shown in the log so it's clear where a set of definitions came from.
+    fn describe(&self) -> String;
+
+    fn load(&self) -> LoadFuture<'_>;
+
+    // True when the last load was served from a cache that should be refreshed.
+    fn wants_refresh(&self) -> bool {
+        false
+    }
+}
+
+// api.sulek.dev, through the on-disk cache unless told to go straight to the network.
+pub struct SulekApiSource {
+    url: String,
+    release: String,
+    cache: DefinitionCache,
+    use_cache: bool,
+    served_from_cache: AtomicBool,
+}
+
+impl SulekApiSource {
+    pub fn new(release: &str, cache: DefinitionCache, use_cache: bool) -> Self {
+        SulekApiSource {
+            url: SULEK_URL.to_owned(),
+            release: release.to_owned(),
+            cache,
+            use_cache,
+            served_from_cache: AtomicBool::new(false),
+        }
+    }
+
+    // Downloads the definitions and keeps a copy of the response for the next start.
+    async fn fetch(&self) -> Result<Vec<MessageInfo>, DefinitionError> {
+        // don't let a dead network hold up startup for long
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()?;
+        let response = client
+            .get(format!("{}/{}/messages", self.url, self.release))
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<Value>()
+            .await?;
+        let messages = definitions::parse_sulek(&response)?;
+
+        if let Err(e) = self.cache.write(&self.release, &response) {
+            ConsoleLogger::warning(format!(
+                "Could not cache message definitions at {}: {}",
+                self.cache.path(&self.release).display(),
+                e
+            ));
+        }
+        Ok(messages)
+    }
+}
+
+impl MessageSource for SulekApiSource {
+    fn describe(&self) -> String {
+        format!("api.sulek.dev ({})", self.release)
+    }
+
+    // A fresh cache wins outright. An expired one is only used when the API can't be reached.
+    fn load(&self) -> LoadFuture<'_> {
+        Box::pin(async move {
+            let cached = match self.use_cache {
+                true => self.cache.read(&self.release).ok(),
+                false => None,
+            };
+            if let Some(cached) = &cached {
+                if !cached.expired {
+                    self.served_from_cache.store(true, Ordering::Relaxed);
+                    return Ok(cached.messages.clone());
+                }
+            }
+
+            match (self.fetch().await, cached) {
+                (Ok(messages), _) => Ok(messages),
+                (Err(e), Some(cached)) => {
+                    ConsoleLogger::warning(format!(
+                        "Could not fetch definitions for {} ({}), using the cached copy from {} hours ago",
+                        self.release, e, cached.age_hours
+                    ));
+                    self.served_from_cache.store(true, Ordering::Relaxed);
+                    Ok(cached.messages)
+                }
+                (Err(e), None) => Err(e),
+            }
+        })
+    }
+
+    fn wants_refresh(&self) -> bool {
+        self.served_from_cache.load(Ordering::Relaxed)
+    }
+}
+
+// A local file in the sulek.dev shape, as JSON or (by extension) TOML:
+//
+//   [[messages.incoming]]
+//   id = 1066
+//   name = "Chat"
+pub struct FileSource {
+    path: PathBuf,
+}
+
+impl FileSource {
+    pub fn new(path: &str) -> Self {
+        FileSource {
+            path: PathBuf::from(path),
+        }
+    }
+}
+
+impl MessageSource for FileSource {
+    fn describe(&self) -> String {
+        self.path.display().to_string()
+    }
+
+    fn load(&self) -> LoadFuture<'_> {
+        Box::pin(async move { definitions::parse_sulek(&read_json_or_toml(&self.path)?) })
+    }
+}
+
+// G-Earth's message files, which map header ids to names and hashes per direction:
+//
+//   {"Incoming": [{"Id": 1066, "Name": "Chat", "Hash": "...", "Structure": "iSii"}], "Outgoing": [...]}
+//
+// Entries without a name (G-Earth leaves unknown ones as null) are skipped.
+pub struct GEarthSource {
+    path: PathBuf,
+}
+
+impl GEarthSource {
+    pub fn new(path: &str) -> Self {
+        GEarthSource {
+            path: PathBuf::from(path),
+        }
+    }
+}
+
+impl MessageSource for GEarthSource {
+    fn describe(&self) -> String {
+        format!("{} (G-Earth)", self.path.display())
+    }
+
+    fn load(&self) -> LoadFuture<'_> {
+        Box::pin(async move { parse_gearth(&read_json_or_toml(&self.path)?) })
+    }
+}
+
+pub fn read_json_or_toml(path: &Path) -> Result<Value, DefinitionError> {
+    let contents = std::fs::read_to_string(path)?;
+    if path.extension().and_then(|extension| extension.to_str()) == Some("toml") {
+        let toml = toml::from_str::<toml::Value>(&contents)
+            .map_err(|e| DefinitionError::Schema(format!("invalid TOML: {}", e)))?;
+        return Ok(serde_json::to_value(toml)?);
+    }
+    Ok(serde_json::from_str(&contents)?)
+}
+
+pub fn parse_gearth(json: &Value) -> Result<Vec<MessageInfo>, DefinitionError> {
+    let mut messages = Vec::new();
+
+    for (key, direction) in [("Incoming", Direction::In), ("Outgoing", Direction::Out)] {
+        let Some(entries) = json.get(key).and_then(|entries| entries.as_array()) else {
+            return Err(DefinitionError::Schema(format!("missing \"{}\" list", key)));
+        };
+
+        for entry in entries {
+            let header = entry
+                .get("Id")
+                .and_then(|header| header.as_u64())
+                .filter(|header| *header <= u16::MAX as u64)
+                .ok_or_else(|| DefinitionError::Schema(format!("bad {} entry {}", key, entry)))?;
+            let Some(name) = entry.get("Name").and_then(|name| name.as_str()) else {
+                continue;
+            };
+
+            let mut message = MessageInfo::new(direction, header as u16, name);
+            message.hash = entry
+                .get("Hash")
+                .and_then(|hash| hash.as_str())
+                .map(str::to_owned);
+            message.structure = entry
+                .get("Structure")
+                .and_then(|structure| structure.as_str())
+                .filter(|structure| !structure.is_empty())
+                .map(str::to_owned);
+            messages.push(message);
+        }
+    }
+
+    Ok(messages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn falls_back_to_an_expired_cache_when_offline() {
+        let directory =
+            std::env::temp_dir().join(format!("hablog-offline-cache-{}", std::process::id()));
+        let cache = DefinitionCache::new(directory.to_str().unwrap(), Duration::ZERO);
+        cache
+            .write(
+                "OFFLINE",
+                &json!({"messages": {"incoming": [{"id": 1066, "name": "Chat"}], "outgoing": []}}),
+            )
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(1100));
+        assert!(cache.read("OFFLINE").unwrap().expired);
+
+        let mut source = SulekApiSource::new("OFFLINE", cache.clone(), true);
+        // nothing listens there, so the fetch fails right away
+        source.url = String::from("http://127.0.0.1:1");
+        let messages = source.load().await.unwrap();
+
+        assert_eq!(messages[0].name, "Chat");
+        assert!(source.wants_refresh());
+        assert!(cache.path("OFFLINE").exists());
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn parses_gearth_files() {
+        let messages = parse_gearth(&json!({
+            "Incoming": [
+                {"Id": 1066, "Name": "Chat", "Hash": "abc", "Structure": "iSii"},
+                {"Id": 1067, "Name": null, "Hash": "def"}
+            ],
+            "Outgoing": [{"Id": 4000, "Name": "ClientHello"}]
+        }))
+        .unwrap();
+
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].name, "Chat");
+        assert_eq!(messages[0].hash.as_deref(), Some("abc"));
+        assert_eq!(messages[0].structure.as_deref(), Some("iSii"));
+        assert_eq!(messages[1].direction, Direction::Out);
+        assert!(parse_gearth(&json!({"Incoming": []})).is_err());
+    }
+
+    #[test]
+    fn reads_toml_files_in_the_sulek_shape() {
+        let path =
+            std::env::temp_dir().join(format!("hablog-messages-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"
+                [[messages.incoming]]
+                id = 1066
+                name = "Chat"
+
+                [[messages.outgoing]]
+                id = 4000
+                name = "ClientHello"
+            "#,
+        )
+        .unwrap();
+
+        let source = FileSource::new(path.to_str().unwrap());
+        let messages = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(source.load())
+            .unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[1].name, "ClientHello");
+    }
+}
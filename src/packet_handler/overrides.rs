@@ -0,0 +1,275 @@
+use crate::packet_handler::definitions::DefinitionError;
+use crate::packet_handler::direction::Direction;
+use crate::packet_handler::message_registry::{MessageInfo, MessageRegistry};
+use crate::packet_handler::message_source;
+use crate::packet_handler::structure::Structure;
+use serde_json::Value;
+use std::path::Path;
+
+// Local corrections applied on top of the loaded definitions, JSON or TOML:
+//
+//   {
+//     "incoming": {
+//       "1066": {"name": "Chat", "aliases": ["Talk"], "structure": "isii"},
+//       "Pong": {"hidden": true}
+//     },
+//     "outgoing": { ... }
+//   }
+//
+// A key is either a header id or a message name. Naming a header the definitions don't have adds it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Overrides {
+    entries: Vec<Override>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Override {
+    pub direction: Direction,
+    pub target: Target,
+    pub name: Option<String>,
+    pub aliases: Vec<String>,
+    pub structure: Option<String>,
+    pub hidden: Option<bool>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    Header(u16),
+    Name(String),
+}
+
+// An override, or part of one, that couldn't be applied.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Unapplied<'a> {
+    // matches no message
+    Unmatched(&'a Override),
+    // the rename would take a name header `owner` already has. Everything else in the override
+    // still applied, unless it was adding a new message, which can't go in without a name.
+    NameTaken { entry: &'a Override, owner: u16 },
+}
+
+impl Overrides {
+    pub fn load(path: &Path) -> Result<Overrides, DefinitionError> {
+        Self::parse(&message_source::read_json_or_toml(path)?)
+    }
+
+    pub fn parse(json: &Value) -> Result<Overrides, DefinitionError> {
+        let mut entries = Vec::new();
+
+        for (key, direction) in [("incoming", Direction::In), ("outgoing", Direction::Out)] {
+            let Some(messages) = json.get(key) else {
+                continue;
+            };
+            let messages = messages
+                .as_object()
+                .ok_or_else(|| DefinitionError::Schema(format!("\"{}\" must be a table", key)))?;
+
+            for (target, fields) in messages {
+                entries.push(Self::parse_entry(direction, target, fields)?);
+            }
+        }
+
+        Ok(Overrides { entries })
+    }
+
+    fn parse_entry(
+        direction: Direction,
+        target: &str,
+        fields: &Value,
+    ) -> Result<Override, DefinitionError> {
+        let bad =
+            |reason: &str| DefinitionError::Schema(format!("override {}: {}", target, reason));
+
+        let structure = fields
+            .get("structure")
+            .and_then(|v| v.as_str())
+            .map(str::to_owned);
+        if let Some(signature) = &structure {
+            Structure::parse(signature).map_err(|e| bad(&e.to_string()))?;
+        }
+        let aliases = match fields.get("aliases") {
+            None => Vec::new(),
+            Some(aliases) => aliases
+                .as_array()
+                .and_then(|aliases| {
+                    aliases
+                        .iter()
+                        .map(|alias| alias.as_str().map(str::to_owned))
+                        .collect::<Option<Vec<_>>>()
+                })
+                .ok_or_else(|| bad("aliases must be a list of names"))?,
+        };
+
+        Ok(Override {
+            direction,
+            target: match target.parse::<u16>() {
+                Ok(header) => Target::Header(header),
+                Err(_) => Target::Name(target.to_owned()),
+            },
+            name: fields
+                .get("name")
+                .and_then(|v| v.as_str())
+                .map(str::to_owned),
+            aliases,
+            structure,
+            hidden: fields.get("hidden").and_then(|v| v.as_bool()),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // Returns what couldn't be applied, so it can be reported.
+    pub fn apply(&self, registry: &mut MessageRegistry) -> Vec<Unapplied<'_>> {
+        let mut unapplied = Vec::new();
+
+        for entry in &self.entries {
+            let existing = match &entry.target {
+                Target::Header(header) => registry.get(entry.direction, *header),
+                Target::Name(name) => registry.find(entry.direction, name),
+            }
+            .cloned();
+
+            let adding = existing.is_none();
+            let mut message = match (existing, &entry.target, &entry.name) {
+                (Some(message), _, _) => message,
+                (None, Target::Header(header), Some(name)) => {
+                    MessageInfo::new(entry.direction, *header, name)
+                }
+                _ => {
+                    unapplied.push(Unapplied::Unmatched(entry));
+                    continue;
+                }
+            };
+
+            if let Some(name) = &entry.name {
+                // taking a name another header already has would quietly push that message out
+                match registry
+                    .find(entry.direction, name)
+                    .filter(|owner| owner.header != message.header)
+                {
+                    Some(owner) => {
+                        unapplied.push(Unapplied::NameTaken {
+                            entry,
+                            owner: owner.header,
+                        });
+                        if adding {
+                            continue;
+                        }
+                    }
+                    None => message.name = name.clone(),
+                }
+            }
+            for alias in &entry.aliases {
+                if !message.aliases.contains(alias) {
+                    message.aliases.push(alias.clone());
+                }
+            }
+            if entry.structure.is_some() {
+                message.structure = entry.structure.clone();
+            }
+            if let Some(hidden) = entry.hidden {
+                message.hidden = hidden;
+            }
+            registry.insert(message);
+        }
+
+        unapplied
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn registry() -> MessageRegistry {
+        let mut registry = MessageRegistry::new();
+        registry.insert(MessageInfo::new(Direction::In, 1066, "Chat"));
+        registry.insert(MessageInfo::new(Direction::Out, 2596, "Pong"));
+        registry
+    }
+
+    #[test]
+    fn renames_aliases_structures_and_hides() {
+        let overrides = Overrides::parse(&json!({
+            "incoming": {
+                "1066": {"name": "RoomChat", "aliases": ["Talk"], "structure": "isii"}
+            },
+            "outgoing": {
+                "Pong": {"hidden": true}
+            }
+        }))
+        .unwrap();
+        let mut registry = registry();
+
+        assert!(overrides.apply(&mut registry).is_empty());
+
+        let chat = registry.get(Direction::In, 1066).unwrap();
+        assert_eq!(chat.name, "RoomChat");
+        assert_eq!(chat.structure.as_deref(), Some("isii"));
+        assert!(registry.find(Direction::In, "Chat").is_none());
+        assert_eq!(registry.find(Direction::In, "Talk").unwrap().header, 1066);
+        assert!(registry.get(Direction::Out, 2596).unwrap().hidden);
+    }
+
+    #[test]
+    fn adds_missing_messages_and_reports_unmatched() {
+        let overrides = Overrides::parse(&json!({
+            "incoming": {
+                "3000": {"name": "Secret"},
+                "3001": {"hidden": true},
+                "Nope": {"hidden": true}
+            }
+        }))
+        .unwrap();
+        let mut registry = registry();
+
+        let unapplied = overrides.apply(&mut registry);
+
+        assert_eq!(registry.get(Direction::In, 3000).unwrap().name, "Secret");
+        assert_eq!(unapplied.len(), 2);
+        assert!(unapplied
+            .iter()
+            .all(|unapplied| matches!(unapplied, Unapplied::Unmatched(_))));
+    }
+
+    #[test]
+    fn skips_only_the_rename_onto_a_taken_name() {
+        let overrides = Overrides::parse(&json!({
+            "outgoing": {"2596": {"name": "Ping"}},
+            "incoming": {
+                "1066": {"name": "Users", "hidden": true, "aliases": ["Talk"]},
+                "3000": {"name": "Users"}
+            }
+        }))
+        .unwrap();
+        let mut registry = registry();
+        registry.insert(MessageInfo::new(Direction::In, 1067, "Users"));
+
+        let unapplied = overrides.apply(&mut registry);
+
+        assert_eq!(unapplied.len(), 2);
+        assert!(unapplied
+            .iter()
+            .all(|unapplied| matches!(unapplied, Unapplied::NameTaken { owner: 1067, .. })));
+        assert_eq!(registry.get(Direction::Out, 2596).unwrap().name, "Ping");
+        let chat = registry.get(Direction::In, 1066).unwrap();
+        assert_eq!(chat.name, "Chat");
+        assert!(chat.hidden);
+        assert_eq!(registry.find(Direction::In, "Talk").unwrap().header, 1066);
+        assert_eq!(registry.find(Direction::In, "Users").unwrap().header, 1067);
+        assert!(registry.get(Direction::In, 3000).is_none());
+    }
+
+    #[test]
+    fn rejects_bad_structures() {
+        assert!(Overrides::parse(&json!({"incoming": {"1": {"structure": "i{"}}})).is_err());
+        assert!(Overrides::parse(&json!({"incoming": {"1": {"aliases": "Talk"}}})).is_err());
+    }
+}
@@ -1,6 +1,11 @@
-use byteorder::{BigEndian, LittleEndian, ReadBytesExt};
-use std::io::Cursor;
-use std::io::Read;
+use crate::packet_handler::direction::Direction;
+use crate::packet_handler::expression::escape;
+use crate::packet_handler::packet_error::PacketError;
+use std::fmt;
+
+// length prefix (4 bytes) + header (2 bytes)
+pub const BODY_OFFSET: usize = 6;
+
 #[derive(Hash, Eq, PartialEq, Debug, Clone)]
 pub struct Packet {
     pub packet_in_bytes: Option<Vec<u8>>,
@@ -8,7 +13,7 @@ pub struct Packet {
     pub position: usize,
     pub name: Option<String>,
     pub header: Option<u16>,
-    pub direction: &'static str, // Incoming or Outgoing. Never changes thus I think it's better to be static?
+    pub direction: Direction,
 }
 
 impl Packet {
@@ -16,104 +21,231 @@ impl Packet {
         packet: Option<Vec<u8>>,
         name: Option<String>,
         header: Option<u16>,
-        direction: &'static str,
+        direction: Direction,
     ) -> Self {
         let bytes = packet.clone();
         Packet {
-            packet_in_bytes: Some(packet).unwrap(),
+            packet_in_bytes: packet,
             bytes: bytes.unwrap_or_default(),
-            position: 0,
-            name: Some(name).unwrap(),
+            position: BODY_OFFSET,
+            name,
             header,
             direction,
         }
     }
 
-    fn read_u32(&mut self, index: Option<usize>) -> u32 {
-        if let Some(index) = index {
-            self.position = index;
+    // Borrows `length` bytes starting at `index` without touching the cursor.
+    fn slice_at(&self, index: usize, length: usize) -> Result<&[u8], PacketError> {
+        let available = self.bytes.len().saturating_sub(index);
+        if available < length {
+            return Err(PacketError::Truncated {
+                needed: length,
+                available,
+            });
         }
-        let value = &self.bytes[self.position..];
-        self.position += 4;
-        let mut cursor = Cursor::new(value);
-        cursor.read_u32::<BigEndian>().unwrap()
+        Ok(&self.bytes[index..index + length])
     }
 
-    fn read_short(&mut self, index: Option<usize>) -> u16 {
-        if let Some(index) = index {
-            self.position = index;
-        }
-        let value = &self.bytes[self.position..];
-        self.position += 2;
-        let mut cursor = Cursor::new(value);
-        match cursor.read_u16::<BigEndian>() {
-            Ok(value) => value,
-            Err(_) => 0,
-        }
+    fn array_at<const N: usize>(&self, index: usize) -> Result<[u8; N], PacketError> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.slice_at(index, N)?);
+        Ok(array)
     }
 
-    pub fn read_long(&mut self, index: Option<usize>) -> u32 {
-        if let Some(index) = index {
-            self.position = index;
-        }
-        let value = &self.bytes[self.position..];
-        self.position += 4;
-        let mut cursor = Cursor::new(value);
-        cursor.read_u32::<BigEndian>().unwrap()
+    // Takes `length` bytes at the cursor and moves past them.
+    // On failure the cursor stays where it was.
+    fn take(&mut self, length: usize) -> Result<&[u8], PacketError> {
+        let start = self.position;
+        self.slice_at(start, length)?;
+        self.position += length;
+        Ok(&self.bytes[start..start + length])
     }
 
-    pub fn get_header(&mut self) -> u16 {
-        self.read_short(Some(4))
+    fn take_array<const N: usize>(&mut self) -> Result<[u8; N], PacketError> {
+        let array = self.array_at(self.position)?;
+        self.position += N;
+        Ok(array)
     }
 
-    pub fn get_body(&mut self) -> Vec<u8> {
-        let bytes = if let Some(bytes) = self.packet_in_bytes.clone() {
-            self.read_bytes(bytes.len() - 6, Some(6))
-        } else {
-            Vec::new()
-        };
-        bytes
+    pub fn get_header(&self) -> Result<u16, PacketError> {
+        Ok(u16::from_be_bytes(self.array_at(4)?))
     }
 
-    pub fn read_bytes(&mut self, length: usize, index: Option<usize>) -> Vec<u8> {
-        if let Some(index) = index {
-            self.position = index;
+    pub fn get_body(&self) -> Result<Vec<u8>, PacketError> {
+        if self.packet_in_bytes.is_none() {
+            return Ok(Vec::new());
         }
-        let value = &self.bytes[self.position..];
-        self.position += length;
-        let mut cursor = Cursor::new(value);
-        let mut bytes = vec![0; length];
-        cursor.read_exact(&mut bytes).unwrap();
-        bytes
+
+        let declared = self.read_length()?;
+        let actual = self.bytes.len() - 4;
+        if (declared as usize) < 2 || declared as usize != actual {
+            return Err(PacketError::InvalidLength { declared, actual });
+        }
+        Ok(self.bytes[BODY_OFFSET..].to_vec())
     }
 
-    pub fn read_byte(&mut self) -> u8 {
-        let value = self.bytes[self.position];
-        self.position += 1;
-        value
+    pub fn read_length(&self) -> Result<u32, PacketError> {
+        Ok(u32::from_be_bytes(self.array_at(0)?))
     }
 
     pub fn total_bytes(&self) -> usize {
         self.bytes.len()
     }
-    pub fn read_length(&mut self) -> u32 {
-        self.read_u32(Some(0))
+
+    // The readers below all work on the body through `position`, which starts right after the header.
+
+    pub fn read_int(&mut self) -> Result<i32, PacketError> {
+        Ok(i32::from_be_bytes(self.take_array()?))
     }
 
-    pub fn to_string(&self) -> String {
-        let mut packet_string = String::new();
+    pub fn read_short(&mut self) -> Result<i16, PacketError> {
+        Ok(i16::from_be_bytes(self.take_array()?))
+    }
 
-        for x in &self.bytes {
-            // Check if byte is a control character or not
-            if (*x < 32 && *x >= 0) || *x == 93 || *x == 91 || *x == 125 || *x == 123 || *x == 127 {
-                packet_string.push('[');
-                packet_string.push_str(&x.to_string());
-                packet_string.push(']');
-            } else {
-                packet_string.push(*x as char);
-            }
+    pub fn read_long(&mut self) -> Result<i64, PacketError> {
+        Ok(i64::from_be_bytes(self.take_array()?))
+    }
+
+    pub fn read_byte(&mut self) -> Result<u8, PacketError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, PacketError> {
+        Ok(self.read_byte()? != 0)
+    }
+
+    pub fn read_bytes(&mut self, length: usize) -> Result<Vec<u8>, PacketError> {
+        Ok(self.take(length)?.to_vec())
+    }
+
+    // Strings are a u16 length followed by that many bytes of UTF-8.
+    // A bad string leaves the cursor untouched so callers can fall back to reading raw bytes.
+    pub fn read_string(&mut self) -> Result<String, PacketError> {
+        let start = self.position;
+        let length = self.read_short()? as u16 as usize;
+        let string = self
+            .read_bytes(length)
+            .and_then(|bytes| String::from_utf8(bytes).map_err(|_| PacketError::InvalidUtf8));
+        if string.is_err() {
+            self.position = start;
         }
+        string
+    }
+
+    pub fn peek(&self, length: usize) -> Result<&[u8], PacketError> {
+        self.slice_at(self.position, length)
+    }
+
+    pub fn skip(&mut self, length: usize) -> Result<(), PacketError> {
+        self.take(length).map(|_| ())
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.bytes.len().saturating_sub(self.position)
+    }
+
+    pub fn reset(&mut self) {
+        self.position = BODY_OFFSET;
+    }
+}
+
+impl fmt::Display for Packet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", escape(&self.bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet_with_body(body: &[u8]) -> Packet {
+        let mut bytes = ((2 + body.len()) as u32).to_be_bytes().to_vec();
+        bytes.extend_from_slice(&1066u16.to_be_bytes());
+        bytes.extend_from_slice(body);
+        Packet::new(Some(bytes), None, None, Direction::In)
+    }
+
+    #[test]
+    fn reads_typed_fields_from_the_body() {
+        let mut body = Vec::new();
+        body.extend_from_slice(&(-42i32).to_be_bytes());
+        body.extend_from_slice(&[0, 5]);
+        body.extend_from_slice(b"hello");
+        body.push(1);
+        body.extend_from_slice(&(-7i16).to_be_bytes());
+        body.extend_from_slice(&(1i64 << 40).to_be_bytes());
+        let mut packet = packet_with_body(&body);
+
+        assert_eq!(packet.get_header().unwrap(), 1066);
+        assert_eq!(packet.read_int().unwrap(), -42);
+        assert_eq!(packet.read_string().unwrap(), "hello");
+        assert!(packet.read_bool().unwrap());
+        assert_eq!(packet.read_short().unwrap(), -7);
+        assert_eq!(packet.read_long().unwrap(), 1 << 40);
+        assert_eq!(packet.remaining(), 0);
+    }
+
+    #[test]
+    fn cursor_can_peek_skip_and_reset() {
+        let mut packet = packet_with_body(&[0, 0, 0, 9, 0, 0, 0, 3]);
+
+        assert_eq!(packet.peek(4).unwrap(), &[0, 0, 0, 9]);
+        assert_eq!(packet.remaining(), 8);
+        packet.skip(4).unwrap();
+        assert_eq!(packet.read_int().unwrap(), 3);
+        packet.reset();
+        assert_eq!(packet.read_int().unwrap(), 9);
+        assert_eq!(packet.get_header().unwrap(), 1066);
+        assert_eq!(packet.read_int().unwrap(), 3);
+    }
+
+    #[test]
+    fn short_reads_are_errors_not_panics() {
+        let mut packet = packet_with_body(&[0, 0, 1]);
+
+        assert_eq!(
+            packet.read_int(),
+            Err(PacketError::Truncated {
+                needed: 4,
+                available: 3
+            })
+        );
+        assert_eq!(packet.remaining(), 3);
+        assert!(packet.read_long().is_err());
+        assert!(packet.peek(4).is_err());
+        assert!(packet.skip(4).is_err());
+        assert_eq!(packet.read_short().unwrap(), 0);
+
+        let stub = Packet::new(Some(vec![0, 0]), None, None, Direction::In);
+        assert!(stub.get_header().is_err());
+        assert!(stub.read_length().is_err());
+    }
+
+    #[test]
+    fn string_errors_leave_the_cursor_in_place() {
+        let mut packet = packet_with_body(&[0, 9, b'a']);
+        assert!(matches!(
+            packet.read_string(),
+            Err(PacketError::Truncated { .. })
+        ));
+        assert_eq!(packet.remaining(), 3);
+
+        let mut packet = packet_with_body(&[0, 2, 0xff, 0xfe]);
+        assert_eq!(packet.read_string(), Err(PacketError::InvalidUtf8));
+        assert_eq!(packet.remaining(), 4);
+    }
+
+    #[test]
+    fn body_rejects_a_mismatched_length_prefix() {
+        let packet = Packet::new(Some(vec![0, 0, 0, 9, 0, 1, 2]), None, None, Direction::In);
 
-        packet_string
+        assert_eq!(
+            packet.get_body(),
+            Err(PacketError::InvalidLength {
+                declared: 9,
+                actual: 3
+            })
+        );
     }
 }
@@ -0,0 +1,147 @@
+use crate::packet_handler::direction::Direction;
+use crate::packet_handler::message_registry::MessageRegistry;
+use crate::packet_handler::packet::Packet;
+use crate::packet_handler::packet_value::PacketValue;
+
+#[derive(Debug, Clone)]
+pub struct PacketBuilder {
+    header: u16,
+    name: Option<String>,
+    direction: Direction,
+    body: Vec<u8>,
+}
+
+impl PacketBuilder {
+    pub fn new(header: u16, direction: Direction) -> Self {
+        PacketBuilder {
+            header,
+            name: None,
+            direction,
+            body: Vec::new(),
+        }
+    }
+
+    // Looks the header up in the definitions loaded by `fetch_packets`.
+    pub fn from_name(name: &str, direction: Direction) -> Option<Self> {
+        let header = MessageRegistry::current().find(direction, name)?.header;
+        let mut builder = Self::new(header, direction);
+        builder.name = Some(name.to_owned());
+        Some(builder)
+    }
+
+    pub fn append_int(mut self, value: i32) -> Self {
+        self.body.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    pub fn append_short(mut self, value: i16) -> Self {
+        self.body.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    pub fn append_long(mut self, value: i64) -> Self {
+        self.body.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    pub fn append_byte(mut self, value: u8) -> Self {
+        self.body.push(value);
+        self
+    }
+
+    pub fn append_bool(self, value: bool) -> Self {
+        self.append_byte(value as u8)
+    }
+
+    // The wire length is a u16 so anything longer than that gets cut off rather than breaking the frame.
+    // The cut backs off to a character boundary so the string stays valid UTF-8.
+    pub fn append_string(mut self, value: &str) -> Self {
+        let mut end = value.len().min(u16::MAX as usize);
+        while !value.is_char_boundary(end) {
+            end -= 1;
+        }
+        let bytes = &value.as_bytes()[..end];
+        self.body
+            .extend_from_slice(&(bytes.len() as u16).to_be_bytes());
+        self.body.extend_from_slice(bytes);
+        self
+    }
+
+    pub fn append_bytes(mut self, value: &[u8]) -> Self {
+        self.body.extend_from_slice(value);
+        self
+    }
+
+    pub fn append_value(self, value: &PacketValue) -> Self {
+        match value {
+            PacketValue::Int(value) => self.append_int(*value),
+            PacketValue::Short(value) => self.append_short(*value as i16),
+            PacketValue::Long(value) => self.append_long(*value),
+            PacketValue::Byte(value) => self.append_byte(*value),
+            PacketValue::Bool(value) => self.append_bool(*value),
+            PacketValue::String(value) => self.append_string(value),
+            PacketValue::Bytes(value) => self.append_bytes(value),
+        }
+    }
+
+    pub fn build(self) -> Packet {
+        let length = (2 + self.body.len()) as u32;
+        let mut bytes = Vec::with_capacity(4 + length as usize);
+        bytes.extend_from_slice(&length.to_be_bytes());
+        bytes.extend_from_slice(&self.header.to_be_bytes());
+        bytes.extend_from_slice(&self.body);
+
+        Packet::new(Some(bytes), self.name, Some(self.header), self.direction)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn built_packet_round_trips_through_the_reader() {
+        let mut packet = PacketBuilder::new(1314, Direction::Out)
+            .append_string("hello")
+            .append_int(-3)
+            .append_bool(true)
+            .append_short(12)
+            .append_long(i64::MAX)
+            .append_bytes(&[7, 8])
+            .build();
+
+        assert_eq!(
+            packet.read_length().unwrap() as usize,
+            packet.total_bytes() - 4
+        );
+        assert_eq!(packet.get_header().unwrap(), 1314);
+        assert_eq!(packet.header, Some(1314));
+        assert_eq!(packet.read_string().unwrap(), "hello");
+        assert_eq!(packet.read_int().unwrap(), -3);
+        assert!(packet.read_bool().unwrap());
+        assert_eq!(packet.read_short().unwrap(), 12);
+        assert_eq!(packet.read_long().unwrap(), i64::MAX);
+        assert_eq!(packet.read_bytes(2).unwrap(), vec![7, 8]);
+        assert_eq!(packet.remaining(), 0);
+    }
+
+    #[test]
+    fn long_strings_are_cut_between_characters() {
+        // 4 byte characters, so u16::MAX lands in the middle of one
+        let long = "\u{1f600}".repeat(20_000);
+        let mut packet = PacketBuilder::new(1, Direction::Out)
+            .append_string(&long)
+            .build();
+
+        let read = packet.read_string().unwrap();
+        assert_eq!(read.len(), 65_532);
+        assert!(long.starts_with(&read));
+    }
+
+    #[test]
+    fn empty_body_is_just_length_and_header() {
+        let packet = PacketBuilder::new(4000, Direction::Out).build();
+
+        assert_eq!(packet.bytes, vec![0, 0, 0, 2, 0x0f, 0xa0]);
+    }
+}
@@ -0,0 +1,33 @@
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PacketError {
+    // Tried to read past the end of the frame.
+    Truncated { needed: usize, available: usize },
+    // The length prefix doesn't match the bytes we actually have.
+    InvalidLength { declared: u32, actual: usize },
+    InvalidUtf8,
+    // A repeated group claimed a negative number of entries.
+    InvalidCount(i32),
+}
+
+impl fmt::Display for PacketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PacketError::Truncated { needed, available } => write!(
+                f,
+                "truncated packet: needed {} bytes but only {} available",
+                needed, available
+            ),
+            PacketError::InvalidLength { declared, actual } => write!(
+                f,
+                "invalid length prefix: declared {} bytes but frame has {}",
+                declared, actual
+            ),
+            PacketError::InvalidUtf8 => write!(f, "string is not valid UTF-8"),
+            PacketError::InvalidCount(count) => write!(f, "invalid repeat count {}", count),
+        }
+    }
+}
+
+impl std::error::Error for PacketError {}
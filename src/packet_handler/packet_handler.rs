@@ -1,171 +1,394 @@
 use crate::logger::ConsoleLogger;
+use crate::packet_handler::definitions;
+use crate::packet_handler::direction::Direction;
+use crate::packet_handler::inference::StructureLearner;
+use crate::packet_handler::interceptor::InterceptorChain;
+use crate::packet_handler::message_registry::MessageRegistry;
 use crate::packet_handler::packet::Packet;
-use std::sync::Arc;
+use crate::packet_handler::packet_error::PacketError;
+use crate::packet_handler::reassembler::{Chunk, FrameReassembler};
+use crate::packet_handler::recent;
+use crate::packet_handler::release::{self, ReleaseGate};
+use crate::packet_handler::structure::{Structure, StructureError};
+use crate::packet_handler::unknown_headers::UnknownHeaders;
+use crate::settings::Settings;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
 use tokio::io::AsyncWriteExt;
-use tokio::sync::Mutex;
+
+const GUESS_CONFIDENCE: f32 = 0.6;
 
 lazy_static::lazy_static! {
-    static ref PACKET_COLLECTION: Mutex<Vec<Packet>> = Mutex::new(vec![]);
+    static ref STRUCTURES: RwLock<HashMap<String, Structure>> = RwLock::new(HashMap::new());
 }
 #[derive(Debug, Clone)]
 pub struct PacketHandler<'a> {
     out_stream: &'a Arc<tokio::sync::Mutex<tokio::net::tcp::OwnedWriteHalf>>,
-    direction: &'static str,
+    direction: Direction,
+    reassembler: FrameReassembler,
+    learner: StructureLearner,
+    settings: Settings,
+    release_gate: ReleaseGate,
+    handshake_seen: bool,
+    interceptors: InterceptorChain,
+    // injected while the stream was desynced, written once it lines up again
+    pending_injections: Vec<Packet>,
+    // forwarded before the release gate opened, named and logged once it has
+    unnamed: Vec<Packet>,
+    // the other end went away, nothing more gets written
+    closed: bool,
 }
 
 impl PacketHandler<'_> {
     pub fn new<'a>(
         out_stream: &'a Arc<tokio::sync::Mutex<tokio::net::tcp::OwnedWriteHalf>>,
-        direction: &'static str,
+        direction: Direction,
+        settings: &Settings,
+        release_gate: ReleaseGate,
+        interceptors: InterceptorChain,
     ) -> PacketHandler<'a> {
         PacketHandler {
             out_stream,
             direction,
+            reassembler: FrameReassembler::new(direction, settings.frame_limits),
+            learner: StructureLearner::new(),
+            settings: settings.clone(),
+            release_gate,
+            handshake_seen: false,
+            interceptors,
+            pending_injections: Vec::new(),
+            unnamed: Vec::new(),
+            closed: false,
         }
     }
 
+    // Nothing is written until a frame is complete and the interceptors have seen it. Bytes we
+    // can't frame (a desynced stream) can't be intercepted either, so those go straight through.
     pub async fn forward(&mut self, buf: &[u8]) {
-        let mut out_stream = self.out_stream.lock().await;
-        out_stream.write_all(buf).await.unwrap();
-        out_stream.flush().await.unwrap();
-        // ConsoleLogger::info(format!("Packet: {}", String::from_utf8_lossy(buf)));
-        self.process(&mut buf.to_vec()).await;
+        let was_desynced = self.reassembler.is_desynced();
+        let chunks = self.reassembler.push(buf);
+        if self.reassembler.is_desynced() != was_desynced {
+            if was_desynced {
+                ConsoleLogger::success(format!("{} stream back in sync", self.direction));
+            } else {
+                ConsoleLogger::warning(format!(
+                    "{} stream looks desynced, passing bytes through raw until it lines up again",
+                    self.direction
+                ));
+            }
+        }
+
+        for chunk in chunks {
+            match chunk {
+                Chunk::Frame(packet) => self.forward_frame(packet).await,
+                Chunk::Raw(bytes) => {
+                    self.write(&bytes).await;
+                    if ConsoleLogger::logs_packets(self.direction) {
+                        ConsoleLogger::log_raw(self.direction, &bytes);
+                    }
+                }
+            }
+        }
+
+        if !self.reassembler.is_desynced() {
+            for packet in std::mem::take(&mut self.pending_injections) {
+                self.inject(packet).await;
+            }
+        }
     }
 
-    pub fn extract_packet(&self, buffer: &mut Vec<u8>) -> Vec<Packet> {
-        // Check if the buffer has enough data for a complete packet via the first 4 bytes which should give length
-        if buffer.len() < 6 {
-            return Vec::new();
+    // Only ever called between reads, and forwarded frames are written whole, so an injected frame
+    // can't land inside another one. Raw passthrough can stop mid-frame though, so while the stream
+    // is desynced injected frames wait.
+    pub async fn inject(&mut self, packet: Packet) {
+        if self.reassembler.is_desynced() {
+            self.pending_injections.push(packet);
+            return;
         }
 
-        // get the packet length from the first 4 bytes
-        let mut packet = Packet::new(Some(buffer.to_vec()), None, None, "Outgoing");
-        let packet_length = packet.read_length() as usize;
+        self.write(&packet.bytes).await;
+        if let Ok(packet) = Self::get_packet_info(packet) {
+            recent::record(&packet, true);
+            ConsoleLogger::info(format!(
+                "Injected {} {}",
+                self.direction,
+                packet
+                    .name
+                    .clone()
+                    .unwrap_or_else(|| packet.header.unwrap_or_default().to_string())
+            ));
+            if ConsoleLogger::logs_packets(self.direction) && !Self::is_hidden(&packet) {
+                let _ = self.process_packet(packet);
+            }
+        }
+    }
 
-        // but does the packet length it told us match the actual length of the buffer?
-        if buffer.len() < packet_length {
-            return Vec::new();
+    async fn forward_frame(&mut self, packet: Packet) {
+        self.check_release(&packet);
+        // the handshake never waits on definitions. Names (and the log) catch up once they're in,
+        // the interceptors don't wait: rules by header, scripts and plugins work without a name.
+        if !self.release_gate.is_open() {
+            self.unnamed.push(packet.clone());
+            let mut unnamed = packet;
+            match unnamed.get_header() {
+                Ok(header) => unnamed.header = Some(header),
+                Err(_) => {
+                    self.write(&unnamed.bytes).await;
+                    return;
+                }
+            }
+            self.intercept(unnamed).await;
+            return;
+        }
+        for packet in std::mem::take(&mut self.unnamed) {
+            self.observe(packet);
         }
 
-        // get header and body from packet
-        let packet_header = &buffer[4..6];
-        let _packet_body = buffer[6..packet_length].to_vec();
-        let header_value = u16::from_be_bytes([packet_header[0], packet_header[1]]);
-        // take the processed packet out of the buffer
-        buffer.drain(0..packet_length);
+        let bytes = packet.bytes.clone();
+        let Some(packet) = self.observe(packet) else {
+            self.write(&bytes).await;
+            return;
+        };
+        self.intercept(packet).await;
+    }
 
-        return Vec::new();
+    // Runs the frame through the interceptors and writes whatever is left of it.
+    async fn intercept(&mut self, packet: Packet) {
+        let name = packet
+            .name
+            .clone()
+            .unwrap_or_else(|| packet.header.unwrap_or_default().to_string());
+        let outcome = self.interceptors.run(packet);
+        if let Some(interceptor) = &outcome.changed_by {
+            ConsoleLogger::info(match outcome.packets.len() {
+                0 => format!("{} {} dropped by {}", self.direction, name, interceptor),
+                n => format!(
+                    "{} {} replaced with {} packets by {}",
+                    self.direction, name, n, interceptor
+                ),
+            });
+        }
+        if !outcome.delay.is_zero() {
+            tokio::time::sleep(outcome.delay).await;
+        }
+        for packet in outcome.packets {
+            self.write(&packet.bytes).await;
+        }
     }
 
-    async fn get_packet_info(mut packet: Packet) -> Packet {
-        let packet_header = packet.get_header();
-        let packet_info = {
-            let collection_lock = PACKET_COLLECTION.lock().await;
-            collection_lock
-                .iter()
-                .find(|packet_info| packet_info.header == Some(packet_header))
-                .cloned()
-        };
-        let packet_info = match packet_info {
-            Some(info) => info,
-            None => packet.clone(),
+    // Names the frame, counts it when it's unknown and logs it. None when it has no header to go by.
+    fn observe(&mut self, packet: Packet) -> Option<Packet> {
+        Self::note_unknown(&packet);
+
+        let bytes = packet.bytes.clone();
+        let packet = match Self::get_packet_info(packet) {
+            Ok(packet) => packet,
+            Err(error) => {
+                // not ours to judge, the other end can deal with it
+                ConsoleLogger::warning(format!(
+                    "Forwarding malformed {} frame untouched ({}): {}",
+                    self.direction,
+                    error,
+                    hex::encode(&bytes)
+                ));
+                return None;
+            }
         };
-        let packet_name = packet_info.name.clone();
 
-        packet.name = packet_name;
+        recent::record(&packet, false);
+        if ConsoleLogger::logs_packets(self.direction) && !Self::is_hidden(&packet) {
+            // a bad body only costs us its log line, the frame still goes through the chain
+            if let Err(error) = self.process_packet(packet.clone()) {
+                ConsoleLogger::warning(format!(
+                    "Skipping malformed {} frame ({}): {}",
+                    self.direction,
+                    error,
+                    hex::encode(&bytes)
+                ));
+            }
+        }
+        Some(packet)
+    }
 
-        packet
+    pub fn is_closed(&self) -> bool {
+        self.closed
     }
 
-    async fn process(&mut self, buffer: &mut Vec<u8>) {
-        let direction_ref = &self.direction;
-        if buffer.len() < 6 || direction_ref == &"Out" {
-            return ();
+    async fn write(&mut self, bytes: &[u8]) {
+        if self.closed {
+            return;
+        }
+        let mut out_stream = self.out_stream.lock().await;
+        let written = match out_stream.write_all(bytes).await {
+            Ok(()) => out_stream.flush().await,
+            Err(error) => Err(error),
+        };
+        if let Err(error) = written {
+            ConsoleLogger::error(format!(
+                "Failed to write {} frame: {}",
+                self.direction, error
+            ));
+            self.closed = true;
         }
+    }
 
-        let mut constructor_packet = Packet::new(Some(buffer.to_vec()), None, None, direction_ref);
+    fn get_packet_info(mut packet: Packet) -> Result<Packet, PacketError> {
+        let packet_header = packet.get_header()?;
+        let registry = MessageRegistry::current();
 
-        let mut packets_collection: Vec<Packet> = Vec::new();
+        packet.name = registry
+            .get(packet.direction, packet_header)
+            .map(|message| message.name.clone());
+        packet.header = Some(packet_header);
 
-        while constructor_packet.total_bytes() >= 4
-            && constructor_packet.total_bytes() - 4 >= constructor_packet.read_length() as usize
-        {
-            let mut new_packet = Packet::new(
-                Some(Self::copy_with_padding(
-                    &buffer,
-                    0,
-                    constructor_packet.read_length() as usize + 4,
-                )),
-                None,
-                None,
-                direction_ref,
-            );
+        Ok(packet)
+    }
 
-            //advance the buffer to the next packet
-            new_packet = Self::get_packet_info(new_packet).await;
-            let packet_clone = new_packet.clone();
-            packets_collection.push(new_packet);
-            *buffer = Self::copy_with_padding(
-                &buffer,
-                constructor_packet.read_length() as usize + 4,
-                buffer.len(),
-            );
-            constructor_packet = Packet::new(Some(buffer.to_vec()), None, None, direction_ref);
-            tokio::spawn(async move { Self::process_packet(packet_clone) }).await;
+    // The client's first packet tells us which release it is. If that isn't the release our
+    // definitions are for, they're swapped in the background and the gate opens once they're in.
+    fn check_release(&mut self, packet: &Packet) {
+        if self.handshake_seen || self.direction != Direction::Out {
+            return;
         }
+        self.handshake_seen = true;
+
+        let release = release::detect_release(packet)
+            .filter(|_| self.settings.detect_release)
+            .filter(|release| MessageRegistry::current().release.as_deref() != Some(release));
+        let Some(release) = release else {
+            self.release_gate.open();
+            return;
+        };
+
+        ConsoleLogger::info(format!(
+            "Client is running {}, loading its message definitions",
+            release
+        ));
+        let settings = self.settings.clone();
+        let release_gate = self.release_gate.clone();
+        tokio::spawn(async move {
+            definitions::install(&settings, &release).await;
+            release_gate.open();
+        });
     }
 
-    pub fn process_packet(mut packet: Packet) {
-        // let packet_name = packet.name.clone().unwrap();
-        // let packet_header = packet.get_header();
-        let packet_body = packet.get_body();
-        // let packet_direction = packet.direction.clone();
+    pub fn process_packet(&mut self, mut packet: Packet) -> Result<(), PacketError> {
+        let packet_body = packet.get_body()?;
+
+        // a signature that doesn't fit this particular packet just means we show it raw
+        if let Some(structure) = Self::get_structure(&packet) {
+            if let Some(header) = packet.header {
+                self.learner.forget(header);
+            }
+            let values = structure.decode(&mut packet).ok();
+            ConsoleLogger::log_packet(packet, &packet_body, values.as_deref(), None);
+            return Ok(());
+        }
+
+        // nobody told us the layout, so show our best guess once we're fairly sure of it
+        let guess = self
+            .learner
+            .learn(&packet)
+            .filter(|guess| guess.confidence >= GUESS_CONFIDENCE);
+        let values = guess
+            .as_ref()
+            .and_then(|guess| Structure::parse(&guess.signature).ok())
+            .and_then(|structure| structure.decode(&mut packet).ok());
 
-        ConsoleLogger::log_packet::<Packet>(packet, &packet_body);
+        ConsoleLogger::log_packet(packet, &packet_body, values.as_deref(), guess.as_ref());
+        Ok(())
     }
 
-    pub fn copy_with_padding(original: &[u8], from: usize, to: usize) -> Vec<u8> {
-        let length = original.len();
-        let padding = if to > length { to - length } else { 0 };
-        let padded_to = to + padding;
+    pub fn register_structure(name: &str, signature: &str) -> Result<(), StructureError> {
+        let structure = Structure::parse(signature)?;
+        STRUCTURES
+            .write()
+            .unwrap()
+            .insert(name.to_owned(), structure);
+        Ok(())
+    }
 
-        let mut result = Vec::with_capacity(padded_to - from);
-        result.extend_from_slice(&original[from..to]);
+    // Counted whether or not this direction is being logged.
+    fn note_unknown(packet: &Packet) {
+        let Ok(header) = packet.get_header() else {
+            return;
+        };
+        if MessageRegistry::current()
+            .get(packet.direction, header)
+            .is_none()
+        {
+            UnknownHeaders::record_seen(packet);
+        }
+    }
+
+    fn is_hidden(packet: &Packet) -> bool {
+        let registry = MessageRegistry::current();
+        packet
+            .header
+            .and_then(|header| registry.get(packet.direction, header))
+            .is_some_and(|message| message.hidden)
+    }
 
-        if padding > 0 {
-            result.extend(vec![0; padding]);
+    // Structures from the settings win over ones that came with the definitions.
+    pub fn get_structure(packet: &Packet) -> Option<Structure> {
+        let name = packet.name.as_ref()?;
+        if let Some(structure) = STRUCTURES.read().unwrap().get(name) {
+            return Some(structure.clone());
         }
 
-        result
+        let registry = MessageRegistry::current();
+        let signature = registry
+            .get(packet.direction, packet.header?)?
+            .structure
+            .as_ref()?;
+        Structure::parse(signature).ok()
     }
+}
 
-    pub async fn fetch_packets() {
-        let url = "https://api.sulek.dev/releases/MAC63-202307041149-55201637/messages";
-        let response = reqwest::get(url)
-            .await
-            .expect("Failed to fetch packets")
-            .json::<serde_json::Value>()
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet_handler::packet_builder::PacketBuilder;
+    use crate::packet_handler::rules::RulesInterceptor;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[tokio::test]
+    async fn rules_apply_while_the_release_gate_is_shut() {
+        let path =
+            std::env::temp_dir().join(format!("hablog-gated-rules-{}.json", std::process::id()));
+        std::fs::write(&path, r#"{"rules": [{"action": "drop", "header": 5}]}"#).unwrap();
+        let interceptors = InterceptorChain::new();
+        interceptors.add(RulesInterceptor::new(path.to_str().unwrap()));
+        std::fs::remove_file(&path).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
             .await
-            .expect("Failed to parse JSON");
-
-        let response_packets = response
-            .get("messages")
-            .and_then(|messages| messages.get("incoming"))
-            .and_then(|incoming| incoming.as_array())
-            .expect("Failed to get incoming packets");
-        for packet in response_packets {
-            let name = packet
-                .get("name")
-                .and_then(|name| name.as_str())
-                .expect("Failed to get packet name")
-                .to_owned();
-            let header = packet
-                .get("id")
-                .and_then(|header| header.as_u64())
-                .expect("Failed to get packet header") as u16;
-
-            let packet = Packet::new(None, Some(name), Some(header), "Out");
-            PACKET_COLLECTION.lock().await.push(packet);
-        }
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        let (_, server_write) = server.into_split();
+        let out_stream = Arc::new(tokio::sync::Mutex::new(server_write));
+
+        let release_gate = ReleaseGate::new(false);
+        let mut handler = PacketHandler::new(
+            &out_stream,
+            Direction::In,
+            &Settings::default(),
+            release_gate.clone(),
+            interceptors,
+        );
+        let dropped = PacketBuilder::new(5, Direction::In).build();
+        let kept = PacketBuilder::new(6, Direction::In).build();
+        handler
+            .forward(&[dropped.bytes.clone(), kept.bytes.clone()].concat())
+            .await;
+        assert!(!release_gate.is_open());
+        drop(handler);
+        drop(out_stream);
+
+        let mut received = Vec::new();
+        client.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, kept.bytes);
     }
 }
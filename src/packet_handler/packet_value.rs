@@ -0,0 +1,40 @@
+use std::fmt;
+
+// A single typed field of a packet body, as read off the wire or typed in by hand.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PacketValue {
+    Int(i32),
+    // unsigned like G-Earth's {u:..}
+    Short(u16),
+    Long(i64),
+    Byte(u8),
+    Bool(bool),
+    String(String),
+    Bytes(Vec<u8>),
+}
+
+// Prints the value the way G-Earth's structured expressions spell it, e.g. {i:0} or {s:"hello"}.
+impl fmt::Display for PacketValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PacketValue::Int(value) => write!(f, "{{i:{}}}", value),
+            PacketValue::Short(value) => write!(f, "{{u:{}}}", value),
+            PacketValue::Long(value) => write!(f, "{{l:{}}}", value),
+            PacketValue::Byte(value) => write!(f, "{{b:{}}}", value),
+            PacketValue::Bool(value) => write!(f, "{{b:{}}}", value),
+            PacketValue::String(value) => {
+                write!(
+                    f,
+                    "{{s:\"{}\"}}",
+                    value.replace('\\', "\\\\").replace('"', "\\\"")
+                )
+            }
+            PacketValue::Bytes(bytes) => {
+                for byte in bytes {
+                    write!(f, "{{b:{}}}", byte)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
@@ -0,0 +1,484 @@
+use crate::logger::ConsoleLogger;
+use crate::packet_handler::direction::Direction;
+use crate::packet_handler::interceptor::{PacketInterceptor, Verdict};
+use crate::packet_handler::message_registry::MessageRegistry;
+use crate::packet_handler::packet::{Packet, BODY_OFFSET};
+use crate::session::SessionHandle;
+use std::fmt;
+use std::path::Path;
+use wasmi::{
+    Caller, Config, Engine, Extern, Linker, Memory, Module, Store, StoreLimits, StoreLimitsBuilder,
+    TypedFunc,
+};
+
+// The plugin ABI, version 1. Frames are whole, length prefix and header included, and directions
+// are 0 for in (server to client) and 1 for out (client to server).
+//
+// A plugin exports:
+//   memory                                      its linear memory
+//   alloc(len: i32) -> i32                      room for a frame of `len` bytes, the host copies it there
+//   on_frame(ptr: i32, len: i32, dir: i32) -> i32
+//                                               0 forward, 1 drop, 2 replace with what it passed to `replace`
+//
+// and may import from "hablog":
+//   replace(ptr: i32, len: i32)                 adds a frame to send instead, when on_frame returns 2
+//   inject(dir: i32, ptr: i32, len: i32)        sends a frame into the session once on_frame returns
+//   log(ptr: i32, len: i32)                     writes a UTF-8 line to the log
+//
+// Length prefixes of frames coming from a plugin are worked out again, so they only need a header and body.
+const HOST_MODULE: &str = "hablog";
+
+const FORWARD: i32 = 0;
+const DROP: i32 = 1;
+const REPLACE: i32 = 2;
+
+// Calls in a row that may fail before the plugin is switched off for the rest of the run. Fuel
+// keeps one call short, this keeps a broken plugin from costing every frame that much.
+const MAX_FAILURES: u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PluginLimits {
+    // wasm instructions per call, roughly. Running out counts as a failed call.
+    pub fuel: u64,
+    // bytes of linear memory a plugin may ever have
+    pub max_memory: usize,
+}
+
+impl Default for PluginLimits {
+    fn default() -> Self {
+        PluginLimits {
+            fuel: 10_000_000,
+            max_memory: 16 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum PluginError {
+    Io(std::io::Error),
+    Wasm(wasmi::Error),
+    // doesn't export what the ABI asks for
+    Abi(String),
+}
+
+impl fmt::Display for PluginError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PluginError::Io(e) => write!(f, "{}", e),
+            PluginError::Wasm(e) => write!(f, "{}", e),
+            PluginError::Abi(message) => write!(f, "doesn't follow the plugin ABI: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for PluginError {}
+
+impl From<std::io::Error> for PluginError {
+    fn from(e: std::io::Error) -> Self {
+        PluginError::Io(e)
+    }
+}
+
+impl From<wasmi::Error> for PluginError {
+    fn from(e: wasmi::Error) -> Self {
+        PluginError::Wasm(e)
+    }
+}
+
+struct HostState {
+    name: String,
+    limits: StoreLimits,
+    // filled in by the plugin during one on_frame call
+    replacements: Vec<Vec<u8>>,
+    injections: Vec<(Direction, Vec<u8>)>,
+}
+
+pub struct WasmPlugin {
+    name: String,
+    store: Store<HostState>,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    on_frame: TypedFunc<(i32, i32, i32), i32>,
+    fuel: u64,
+    session: SessionHandle,
+    failures: u32,
+}
+
+impl WasmPlugin {
+    pub fn load(
+        path: &Path,
+        limits: PluginLimits,
+        session: SessionHandle,
+    ) -> Result<Self, PluginError> {
+        let wasm = std::fs::read(path)?;
+        Self::from_bytes(&path.display().to_string(), &wasm, limits, session)
+    }
+
+    pub fn from_bytes(
+        name: &str,
+        wasm: &[u8],
+        limits: PluginLimits,
+        session: SessionHandle,
+    ) -> Result<Self, PluginError> {
+        let mut config = Config::default();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config);
+        let module = Module::new(&engine, wasm)?;
+
+        let mut store = Store::new(
+            &engine,
+            HostState {
+                name: name.to_owned(),
+                limits: StoreLimitsBuilder::new()
+                    .memory_size(limits.max_memory)
+                    .memories(1)
+                    .instances(1)
+                    .build(),
+                replacements: Vec::new(),
+                injections: Vec::new(),
+            },
+        );
+        store.limiter(|state| &mut state.limits);
+        // the start function gets the same budget as a call
+        store.set_fuel(limits.fuel).map_err(wasmi::Error::from)?;
+
+        let instance = Self::linker(&engine)?
+            .instantiate(&mut store, &module)?
+            .start(&mut store)?;
+        let memory = instance
+            .get_memory(&store, "memory")
+            .ok_or_else(|| PluginError::Abi("no exported memory".to_owned()))?;
+        let alloc = instance
+            .get_typed_func::<i32, i32>(&store, "alloc")
+            .map_err(|e| PluginError::Abi(format!("alloc: {}", e)))?;
+        let on_frame = instance
+            .get_typed_func::<(i32, i32, i32), i32>(&store, "on_frame")
+            .map_err(|e| PluginError::Abi(format!("on_frame: {}", e)))?;
+
+        Ok(WasmPlugin {
+            name: name.to_owned(),
+            store,
+            memory,
+            alloc,
+            on_frame,
+            fuel: limits.fuel,
+            session,
+            failures: 0,
+        })
+    }
+
+    fn linker(engine: &Engine) -> Result<Linker<HostState>, PluginError> {
+        let mut linker = Linker::new(engine);
+        linker
+            .func_wrap(
+                HOST_MODULE,
+                "replace",
+                |mut caller: Caller<'_, HostState>,
+                 ptr: i32,
+                 len: i32|
+                 -> Result<(), wasmi::Error> {
+                    let frame = frame_from(read_memory(&caller, ptr, len)?)?;
+                    caller.data_mut().replacements.push(frame);
+                    Ok(())
+                },
+            )
+            .map_err(wasmi::Error::from)?;
+        linker
+            .func_wrap(
+                HOST_MODULE,
+                "inject",
+                |mut caller: Caller<'_, HostState>,
+                 direction: i32,
+                 ptr: i32,
+                 len: i32|
+                 -> Result<(), wasmi::Error> {
+                    let direction = direction_from(direction)?;
+                    let frame = frame_from(read_memory(&caller, ptr, len)?)?;
+                    caller.data_mut().injections.push((direction, frame));
+                    Ok(())
+                },
+            )
+            .map_err(wasmi::Error::from)?;
+        linker
+            .func_wrap(
+                HOST_MODULE,
+                "log",
+                |caller: Caller<'_, HostState>, ptr: i32, len: i32| -> Result<(), wasmi::Error> {
+                    let message = read_memory(&caller, ptr, len)?;
+                    ConsoleLogger::normal(format!(
+                        "[{}] {}",
+                        caller.data().name,
+                        String::from_utf8_lossy(&message)
+                    ));
+                    Ok(())
+                },
+            )
+            .map_err(wasmi::Error::from)?;
+        Ok(linker)
+    }
+
+    fn call(&mut self, packet: &Packet) -> Result<Verdict, wasmi::Error> {
+        self.store.set_fuel(self.fuel)?;
+        let state = self.store.data_mut();
+        state.replacements.clear();
+        state.injections.clear();
+
+        let len =
+            i32::try_from(packet.bytes.len()).map_err(|_| wasmi::Error::new("frame too large"))?;
+        let ptr = self.alloc.call(&mut self.store, len)?;
+        let offset = usize::try_from(ptr)
+            .map_err(|_| wasmi::Error::new("alloc returned a negative pointer"))?;
+        self.memory
+            .write(&mut self.store, offset, &packet.bytes)
+            .map_err(|e| wasmi::Error::new(format!("alloc returned a bad pointer: {}", e)))?;
+
+        let direction = match packet.direction {
+            Direction::In => 0,
+            Direction::Out => 1,
+        };
+        let verdict = match self.on_frame.call(&mut self.store, (ptr, len, direction))? {
+            FORWARD => Verdict::Forward,
+            DROP => Verdict::Drop,
+            REPLACE => Verdict::Replace(
+                self.store
+                    .data_mut()
+                    .replacements
+                    .drain(..)
+                    .map(|bytes| replacement(packet, bytes))
+                    .collect(),
+            ),
+            other => return Err(wasmi::Error::new(format!("unknown verdict {}", other))),
+        };
+        Ok(verdict)
+    }
+}
+
+fn read_memory(
+    caller: &Caller<'_, HostState>,
+    ptr: i32,
+    len: i32,
+) -> Result<Vec<u8>, wasmi::Error> {
+    let memory = caller
+        .get_export("memory")
+        .and_then(Extern::into_memory)
+        .ok_or_else(|| wasmi::Error::new("no exported memory"))?;
+    let (Ok(offset), Ok(len)) = (usize::try_from(ptr), usize::try_from(len)) else {
+        return Err(wasmi::Error::new("negative pointer or length"));
+    };
+    memory
+        .data(caller)
+        .get(offset..offset.saturating_add(len))
+        .map(<[u8]>::to_vec)
+        .ok_or_else(|| wasmi::Error::new("pointer past the end of memory"))
+}
+
+// Keeps the original's name when the header is the same, otherwise names it afresh.
+fn replacement(original: &Packet, bytes: Vec<u8>) -> Packet {
+    let header = u16::from_be_bytes([bytes[4], bytes[5]]);
+    let name = if original.header == Some(header) {
+        original.name.clone()
+    } else {
+        MessageRegistry::current()
+            .get(original.direction, header)
+            .map(|message| message.name.clone())
+    };
+    Packet::new(Some(bytes), name, Some(header), original.direction)
+}
+
+fn frame_from(mut bytes: Vec<u8>) -> Result<Vec<u8>, wasmi::Error> {
+    if bytes.len() < BODY_OFFSET {
+        return Err(wasmi::Error::new(
+            "a frame needs at least a length and a header",
+        ));
+    }
+    let length = (bytes.len() - 4) as u32;
+    bytes[0..4].copy_from_slice(&length.to_be_bytes());
+    Ok(bytes)
+}
+
+fn direction_from(code: i32) -> Result<Direction, wasmi::Error> {
+    match code {
+        0 => Ok(Direction::In),
+        1 => Ok(Direction::Out),
+        other => Err(wasmi::Error::new(format!("unknown direction {}", other))),
+    }
+}
+
+impl PacketInterceptor for WasmPlugin {
+    fn name(&self) -> String {
+        format!("plugin ({})", self.name)
+    }
+
+    // A plugin that traps or runs out of fuel doesn't get to affect the packet, or inject anything.
+    fn intercept(&mut self, packet: &Packet) -> Verdict {
+        if self.failures >= MAX_FAILURES {
+            return Verdict::Forward;
+        }
+        match self.call(packet) {
+            Ok(verdict) => {
+                self.failures = 0;
+                for (direction, bytes) in self.store.data_mut().injections.drain(..) {
+                    if let Err(e) =
+                        self.session
+                            .send(Packet::new(Some(bytes), None, None, direction))
+                    {
+                        ConsoleLogger::warning(format!("{} could not inject: {}", self.name, e));
+                    }
+                }
+                verdict
+            }
+            Err(e) => {
+                ConsoleLogger::warning(format!("{} failed: {}", self.name, e));
+                self.failures += 1;
+                if self.failures == MAX_FAILURES {
+                    ConsoleLogger::warning(format!(
+                        "{} failed {} times in a row, disabling it",
+                        self.name, MAX_FAILURES
+                    ));
+                }
+                Verdict::Forward
+            }
+        }
+    }
+}
+
+// Every *.wasm file in the directory, in name order. Plugins that fail to load are skipped.
+pub fn load_plugins(
+    directory: &str,
+    limits: PluginLimits,
+    session: &SessionHandle,
+) -> Vec<WasmPlugin> {
+    let Ok(entries) = std::fs::read_dir(directory) else {
+        return Vec::new();
+    };
+    let mut paths: Vec<_> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().and_then(|extension| extension.to_str()) == Some("wasm"))
+        .collect();
+    paths.sort();
+
+    let mut plugins = Vec::new();
+    for path in paths {
+        match WasmPlugin::load(&path, limits, session.clone()) {
+            Ok(plugin) => {
+                ConsoleLogger::normal(format!("Loaded plugin {}", path.display()));
+                plugins.push(plugin);
+            }
+            Err(e) => ConsoleLogger::warning(format!("Could not load {}: {}", path.display(), e)),
+        }
+    }
+    plugins
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet_handler::packet_builder::PacketBuilder;
+
+    // Decides on the low byte of the header: 1 drops, 2 sends the frame twice, 3 also injects it
+    // toward the client, 4 never returns, 5 grows memory past the limit.
+    const PLUGIN: &str = r#"
+        (module
+          (import "hablog" "replace" (func $replace (param i32 i32)))
+          (import "hablog" "inject" (func $inject (param i32 i32 i32)))
+          (memory (export "memory") 1)
+          (func (export "alloc") (param i32) (result i32) (i32.const 1024))
+          (func (export "on_frame") (param $ptr i32) (param $len i32) (param $dir i32) (result i32)
+            (local $header i32)
+            (local.set $header (i32.load8_u (i32.add (local.get $ptr) (i32.const 5))))
+            (if (i32.eq (local.get $header) (i32.const 1)) (then (return (i32.const 1))))
+            (if (i32.eq (local.get $header) (i32.const 2)) (then
+              (call $replace (local.get $ptr) (local.get $len))
+              (call $replace (local.get $ptr) (local.get $len))
+              (return (i32.const 2))))
+            (if (i32.eq (local.get $header) (i32.const 3)) (then
+              (call $inject (i32.const 0) (local.get $ptr) (local.get $len))))
+            (if (i32.eq (local.get $header) (i32.const 4)) (then (loop $forever (br $forever))))
+            (if (i32.eq (local.get $header) (i32.const 5)) (then
+              (if (i32.eq (memory.grow (i32.const 1024)) (i32.const -1)) (then (unreachable)))))
+            (i32.const 0)))
+    "#;
+
+    fn plugin(session: SessionHandle) -> WasmPlugin {
+        let wasm = wat::parse_str(PLUGIN).unwrap();
+        WasmPlugin::from_bytes("test", &wasm, PluginLimits::default(), session).unwrap()
+    }
+
+    fn frame(header: u16) -> Packet {
+        PacketBuilder::new(header, Direction::Out)
+            .append_string("hi")
+            .build()
+    }
+
+    #[test]
+    fn follows_the_verdicts() {
+        let mut plugin = plugin(SessionHandle::new());
+
+        assert_eq!(plugin.intercept(&frame(1)), Verdict::Drop);
+        assert_eq!(plugin.intercept(&frame(7)), Verdict::Forward);
+        let Verdict::Replace(packets) = plugin.intercept(&frame(2)) else {
+            panic!("expected a replacement");
+        };
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[0].bytes, frame(2).bytes);
+
+        let mut named = frame(2);
+        named.header = Some(2);
+        named.name = Some(String::from("PluginTestChat"));
+        let Verdict::Replace(packets) = plugin.intercept(&named) else {
+            panic!("expected a replacement");
+        };
+        assert_eq!(packets[0].name.as_deref(), Some("PluginTestChat"));
+    }
+
+    #[test]
+    fn injects_after_the_call() {
+        let session = SessionHandle::new();
+        let mut injections = session.attach();
+        let mut plugin = plugin(session);
+
+        assert_eq!(plugin.intercept(&frame(3)), Verdict::Forward);
+        let injected = injections.to_client.try_recv().unwrap();
+        assert_eq!(injected.direction, Direction::In);
+        assert_eq!(injected.get_header(), Ok(3));
+    }
+
+    #[test]
+    fn runaway_and_greedy_plugins_are_cut_off() {
+        let mut plugin = plugin(SessionHandle::new());
+
+        assert_eq!(plugin.intercept(&frame(4)), Verdict::Forward);
+        assert_eq!(plugin.intercept(&frame(5)), Verdict::Forward);
+        // still usable afterwards
+        assert_eq!(plugin.intercept(&frame(1)), Verdict::Drop);
+
+        for _ in 0..MAX_FAILURES {
+            plugin.intercept(&frame(4));
+        }
+        // switched off, so it doesn't get to drop anything either
+        assert_eq!(plugin.intercept(&frame(1)), Verdict::Forward);
+    }
+
+    #[test]
+    fn rejects_modules_that_break_the_abi_or_limits() {
+        let no_exports = wat::parse_str("(module (memory (export \"memory\") 1))").unwrap();
+        assert!(matches!(
+            WasmPlugin::from_bytes(
+                "test",
+                &no_exports,
+                PluginLimits::default(),
+                SessionHandle::new()
+            ),
+            Err(PluginError::Abi(_))
+        ));
+
+        let huge = wat::parse_str("(module (memory (export \"memory\") 1024))").unwrap();
+        assert!(WasmPlugin::from_bytes(
+            "test",
+            &huge,
+            PluginLimits::default(),
+            SessionHandle::new()
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,433 @@
+use crate::packet_handler::direction::Direction;
+use crate::packet_handler::packet::Packet;
+
+// Every Habbo frame starts with a 4 byte big endian length followed by a 2 byte header.
+// The length counts the header and the body but not itself.
+pub const LENGTH_PREFIX_SIZE: usize = 4;
+pub const HEADER_SIZE: usize = 2;
+
+// How many frames in a row have to line up before resync trusts an offset. Looking only this far
+// ahead keeps the scan over a desynced read linear instead of reparsing the rest of it from every byte.
+const RESYNC_FRAMES: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameLimits {
+    pub max_frame_size: usize,
+    // Header ids in current releases stay in the low thousands, 8191 (13 bits) leaves them room to
+    // grow while still rejecting most random u16s, which is what makes it useful for spotting a desync.
+    // Raise it with the `max_header` setting if a release ever goes past it.
+    pub max_header: u16,
+}
+
+impl Default for FrameLimits {
+    fn default() -> Self {
+        FrameLimits {
+            max_frame_size: 1024 * 1024,
+            max_header: 8191,
+        }
+    }
+}
+
+impl FrameLimits {
+    fn plausible_length(&self, length: u32) -> bool {
+        let length = length as usize;
+        length >= HEADER_SIZE && length <= self.max_frame_size
+    }
+
+    fn plausible_header(&self, header: u16) -> bool {
+        header <= self.max_header
+    }
+}
+
+// What the reassembler hands back for each read: complete frames while we're in sync,
+// and the untouched bytes while we're not.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Chunk {
+    Frame(Packet),
+    Raw(Vec<u8>),
+}
+
+#[derive(Debug, Clone)]
+pub struct FrameReassembler {
+    buffer: Vec<u8>,
+    direction: Direction,
+    limits: FrameLimits,
+    desynced: bool,
+}
+
+impl FrameReassembler {
+    pub fn new(direction: Direction, limits: FrameLimits) -> Self {
+        FrameReassembler {
+            buffer: Vec::new(),
+            direction,
+            limits,
+            desynced: false,
+        }
+    }
+
+    // Appends whatever the socket gave us and hands back every frame that is now complete.
+    // A partial frame at the end of the chunk stays buffered until the next read completes it.
+    pub fn push(&mut self, data: &[u8]) -> Vec<Chunk> {
+        self.buffer.extend_from_slice(data);
+        if self.desynced {
+            self.resync()
+        } else {
+            self.extract_packets()
+        }
+    }
+
+    pub fn extract_packets(&mut self) -> Vec<Chunk> {
+        let mut chunks = Vec::new();
+        let mut offset = 0;
+
+        while self.buffer.len() - offset >= LENGTH_PREFIX_SIZE {
+            let Some(frame_length) = self.frame_length_at(offset) else {
+                // the stream stopped making sense, give up on framing until we find our footing again
+                self.desynced = true;
+                self.buffer.drain(0..offset);
+                chunks.push(Chunk::Raw(std::mem::take(&mut self.buffer)));
+                return chunks;
+            };
+
+            // not enough data for the whole frame yet, wait for the next read
+            if self.buffer.len() - offset < frame_length {
+                break;
+            }
+
+            chunks.push(self.frame_at(offset, frame_length));
+            offset += frame_length;
+        }
+
+        // take the processed frames out of the buffer, keeping the partial tail
+        self.buffer.drain(0..offset);
+        chunks
+    }
+
+    // While desynced nothing is held back. We look for an offset where a few plausible frames line up
+    // (or run to the end of the read, or to a frame that's still coming in), emit everything
+    // before it raw and pick framing back up there. If there is no such offset the whole read goes out raw.
+    fn resync(&mut self) -> Vec<Chunk> {
+        let buffer = std::mem::take(&mut self.buffer);
+        let Some(start) = (0..buffer.len()).find(|&offset| self.frames_line_up(&buffer, offset))
+        else {
+            return vec![Chunk::Raw(buffer)];
+        };
+
+        self.desynced = false;
+        self.buffer = buffer[start..].to_vec();
+        let mut chunks = Vec::new();
+        if start > 0 {
+            chunks.push(Chunk::Raw(buffer[..start].to_vec()));
+        }
+        chunks.extend(self.extract_packets());
+        chunks
+    }
+
+    // A trailing frame that's cut off only counts once its header checks out and at least one
+    // whole frame came before it, a lone partial frame is too easy to find in noise.
+    fn frames_line_up(&self, buffer: &[u8], mut offset: usize) -> bool {
+        let mut complete = 0;
+        while offset < buffer.len() && complete < RESYNC_FRAMES {
+            let remaining = buffer.len() - offset;
+            match Self::read_frame_length(buffer, offset, &self.limits) {
+                Some(length) if length <= remaining => offset += length,
+                Some(_) => return complete > 0 && remaining >= LENGTH_PREFIX_SIZE + HEADER_SIZE,
+                None => return false,
+            }
+            complete += 1;
+        }
+        true
+    }
+
+    // Length of the whole frame at `offset` including its prefix, or None if the frame
+    // can't possibly be real. The header is only checked once we actually have it.
+    fn frame_length_at(&self, offset: usize) -> Option<usize> {
+        Self::read_frame_length(&self.buffer, offset, &self.limits)
+    }
+
+    fn read_frame_length(buffer: &[u8], offset: usize, limits: &FrameLimits) -> Option<usize> {
+        let length_bytes = buffer.get(offset..offset + LENGTH_PREFIX_SIZE)?;
+        let length = u32::from_be_bytes([
+            length_bytes[0],
+            length_bytes[1],
+            length_bytes[2],
+            length_bytes[3],
+        ]);
+        if !limits.plausible_length(length) {
+            return None;
+        }
+
+        let header_offset = offset + LENGTH_PREFIX_SIZE;
+        if let Some(header_bytes) = buffer.get(header_offset..header_offset + HEADER_SIZE) {
+            let header = u16::from_be_bytes([header_bytes[0], header_bytes[1]]);
+            if !limits.plausible_header(header) {
+                return None;
+            }
+        }
+
+        Some(LENGTH_PREFIX_SIZE + length as usize)
+    }
+
+    fn frame_at(&self, offset: usize, frame_length: usize) -> Chunk {
+        let frame = self.buffer[offset..offset + frame_length].to_vec();
+        Chunk::Frame(Packet::new(Some(frame), None, None, self.direction))
+    }
+
+    pub fn is_desynced(&self) -> bool {
+        self.desynced
+    }
+
+    pub fn buffered_len(&self) -> usize {
+        self.buffer.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(header: u16, body: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::new();
+        frame.extend_from_slice(&((HEADER_SIZE + body.len()) as u32).to_be_bytes());
+        frame.extend_from_slice(&header.to_be_bytes());
+        frame.extend_from_slice(body);
+        frame
+    }
+
+    // A few frames as they were recorded off the wire: a string message, an empty body and a larger payload.
+    fn recorded_frames() -> Vec<Vec<u8>> {
+        vec![
+            frame(1066, &[0, 5, b'h', b'e', b'l', b'l', b'o', 0, 0, 0, 0]),
+            frame(2596, &[]),
+            frame(3123, &(0..=255).collect::<Vec<u8>>()),
+            frame(4000, &[0, 3, b'M', b'A', b'C', 0, 0, 0, 1]),
+        ]
+    }
+
+    fn collect_frames(chunks: Vec<Chunk>) -> Vec<Vec<u8>> {
+        chunks
+            .into_iter()
+            .map(|chunk| match chunk {
+                Chunk::Frame(packet) => packet.bytes,
+                Chunk::Raw(bytes) => panic!("unexpected raw chunk {:?}", bytes),
+            })
+            .collect()
+    }
+
+    fn collect_bytes(chunks: Vec<Chunk>) -> Vec<u8> {
+        chunks
+            .into_iter()
+            .flat_map(|chunk| match chunk {
+                Chunk::Frame(packet) => packet.bytes,
+                Chunk::Raw(bytes) => bytes,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn emits_frames_from_a_single_read() {
+        let frames = recorded_frames();
+        let stream = frames.concat();
+        let mut reassembler = FrameReassembler::new(Direction::In, FrameLimits::default());
+
+        assert_eq!(collect_frames(reassembler.push(&stream)), frames);
+        assert_eq!(reassembler.buffered_len(), 0);
+    }
+
+    #[test]
+    fn survives_a_cut_at_every_byte_offset() {
+        let frames = recorded_frames();
+        let stream = frames.concat();
+
+        for cut in 0..=stream.len() {
+            let mut reassembler = FrameReassembler::new(Direction::In, FrameLimits::default());
+            let mut packets = collect_frames(reassembler.push(&stream[..cut]));
+            packets.extend(collect_frames(reassembler.push(&stream[cut..])));
+
+            assert_eq!(packets, frames, "cut at offset {}", cut);
+            assert_eq!(reassembler.buffered_len(), 0, "cut at offset {}", cut);
+        }
+    }
+
+    #[test]
+    fn survives_two_cuts_at_every_pair_of_offsets() {
+        let frames = recorded_frames();
+        let stream = frames.concat();
+
+        for first in 0..=stream.len() {
+            for second in first..=stream.len() {
+                let mut reassembler = FrameReassembler::new(Direction::Out, FrameLimits::default());
+                let mut packets = collect_frames(reassembler.push(&stream[..first]));
+                packets.extend(collect_frames(reassembler.push(&stream[first..second])));
+                packets.extend(collect_frames(reassembler.push(&stream[second..])));
+
+                assert_eq!(packets, frames, "cuts at {} and {}", first, second);
+            }
+        }
+    }
+
+    #[test]
+    fn survives_one_byte_reads() {
+        let frames = recorded_frames();
+        let stream = frames.concat();
+        let mut reassembler = FrameReassembler::new(Direction::In, FrameLimits::default());
+
+        let packets = stream
+            .iter()
+            .flat_map(|byte| collect_frames(reassembler.push(&[*byte])))
+            .collect::<Vec<_>>();
+
+        assert_eq!(packets, frames);
+    }
+
+    #[test]
+    fn keeps_partial_tail_until_completed() {
+        let data = frame(1066, &[1, 2, 3, 4]);
+        let mut reassembler = FrameReassembler::new(Direction::In, FrameLimits::default());
+
+        assert!(reassembler.push(&data[..7]).is_empty());
+        assert_eq!(reassembler.buffered_len(), 7);
+
+        let packets = reassembler.push(&data[7..]);
+        assert_eq!(collect_frames(packets), vec![data]);
+    }
+
+    #[test]
+    fn tags_packets_with_direction() {
+        let mut reassembler = FrameReassembler::new(Direction::Out, FrameLimits::default());
+        let chunks = reassembler.push(&frame(4000, &[]));
+
+        let Chunk::Frame(packet) = &chunks[0] else {
+            panic!("expected a frame");
+        };
+        assert_eq!(packet.direction, Direction::Out);
+        assert_eq!(packet.get_header().unwrap(), 4000);
+    }
+
+    #[test]
+    fn garbage_length_switches_to_raw_passthrough() {
+        let frames = recorded_frames();
+        let mut garbage = vec![0xde, 0xad, 0xbe, 0xef, 0x13, 0x37];
+        garbage.extend_from_slice(&frames[0]);
+        let mut reassembler = FrameReassembler::new(Direction::In, FrameLimits::default());
+
+        let chunks = reassembler.push(&garbage);
+
+        assert_eq!(chunks, vec![Chunk::Raw(garbage.clone())]);
+        assert!(reassembler.is_desynced());
+        assert_eq!(reassembler.buffered_len(), 0);
+    }
+
+    #[test]
+    fn implausible_header_counts_as_desync() {
+        let limits = FrameLimits {
+            max_frame_size: 1024,
+            max_header: 4000,
+        };
+        let mut reassembler = FrameReassembler::new(Direction::In, limits);
+
+        let chunks = reassembler.push(&frame(9000, &[1, 2, 3]));
+
+        assert!(matches!(chunks.as_slice(), [Chunk::Raw(_)]));
+        assert!(reassembler.is_desynced());
+    }
+
+    #[test]
+    fn frames_before_the_desync_still_come_out() {
+        let frames = recorded_frames();
+        let mut stream = frames[0].clone();
+        stream.extend_from_slice(&[0xff; 10]);
+        let mut reassembler = FrameReassembler::new(Direction::In, FrameLimits::default());
+
+        let chunks = reassembler.push(&stream);
+
+        assert_eq!(
+            chunks,
+            vec![
+                Chunk::Frame(Packet::new(
+                    Some(frames[0].clone()),
+                    None,
+                    None,
+                    Direction::In
+                )),
+                Chunk::Raw(vec![0xff; 10]),
+            ]
+        );
+    }
+
+    #[test]
+    fn resyncs_once_frames_line_up_again() {
+        let frames = recorded_frames();
+        let mut reassembler = FrameReassembler::new(Direction::In, FrameLimits::default());
+        reassembler.push(&[0xff; 7]);
+        assert!(reassembler.is_desynced());
+
+        // joined mid-frame: the tail of something we never saw, then real frames
+        let mut read = vec![0xaa, 0xbb, 0xcc];
+        read.extend_from_slice(&frames.concat());
+        let chunks = reassembler.push(&read);
+
+        assert!(!reassembler.is_desynced());
+        assert_eq!(chunks[0], Chunk::Raw(vec![0xaa, 0xbb, 0xcc]));
+        assert_eq!(collect_frames(chunks[1..].to_vec()), frames);
+    }
+
+    #[test]
+    fn resyncs_with_a_frame_still_coming_in() {
+        let frames = recorded_frames();
+        let mut reassembler = FrameReassembler::new(Direction::In, FrameLimits::default());
+        reassembler.push(&[0xff; 7]);
+
+        let mut read = vec![0xaa, 0xbb, 0xcc];
+        read.extend_from_slice(&frames[0]);
+        read.extend_from_slice(&frames[2][..100]);
+        let chunks = reassembler.push(&read);
+
+        assert!(!reassembler.is_desynced());
+        assert_eq!(chunks[0], Chunk::Raw(vec![0xaa, 0xbb, 0xcc]));
+        assert_eq!(
+            collect_frames(chunks[1..].to_vec()),
+            vec![frames[0].clone()]
+        );
+        assert_eq!(
+            collect_frames(reassembler.push(&frames[2][100..])),
+            vec![frames[2].clone()]
+        );
+    }
+
+    #[test]
+    fn resync_only_looks_a_few_frames_ahead() {
+        let frames = recorded_frames();
+        assert_eq!(frames.len(), RESYNC_FRAMES);
+        let mut reassembler = FrameReassembler::new(Direction::In, FrameLimits::default());
+        reassembler.push(&[0xff; 7]);
+
+        // enough frames to trust the offset, then more junk further on in the same read
+        let mut read = vec![0xaa];
+        read.extend_from_slice(&frames.concat());
+        read.extend_from_slice(&[0xff; 10]);
+        let chunks = reassembler.push(&read);
+
+        assert_eq!(chunks[0], Chunk::Raw(vec![0xaa]));
+        assert_eq!(collect_frames(chunks[1..5].to_vec()), frames);
+        assert_eq!(chunks[5..], [Chunk::Raw(vec![0xff; 10])]);
+        assert!(reassembler.is_desynced());
+    }
+
+    #[test]
+    fn passthrough_never_loses_or_holds_bytes() {
+        let frames = recorded_frames();
+        let mut stream = vec![0x7f, 0x00, 0x00, 0x00, 0x01];
+        stream.extend_from_slice(&frames.concat());
+
+        for cut in 0..=stream.len() {
+            let mut reassembler = FrameReassembler::new(Direction::In, FrameLimits::default());
+            let mut bytes = collect_bytes(reassembler.push(&stream[..cut]));
+            bytes.extend(collect_bytes(reassembler.push(&stream[cut..])));
+            bytes.extend(collect_bytes(reassembler.push(&[])));
+
+            assert_eq!(bytes.len() + reassembler.buffered_len(), stream.len());
+            assert_eq!(bytes, stream[..bytes.len()], "cut at offset {}", cut);
+        }
+    }
+}
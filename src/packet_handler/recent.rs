@@ -0,0 +1,140 @@
+use crate::packet_handler::direction::Direction;
+use crate::packet_handler::expression;
+use crate::packet_handler::packet::Packet;
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::Mutex;
+
+const CAPACITY: usize = 200;
+// Frames can be up to a megabyte, so the count alone doesn't bound the memory. The newest frame
+// is always kept, however big.
+const MAX_BYTES: usize = 4 * 1024 * 1024;
+
+lazy_static::lazy_static! {
+    static ref RECENT: Mutex<RecentPackets> = Mutex::new(RecentPackets::default());
+}
+
+#[derive(Debug, Default)]
+struct RecentPackets {
+    packets: VecDeque<RecentPacket>,
+    bytes: usize,
+}
+
+impl RecentPackets {
+    fn push(&mut self, packet: RecentPacket) {
+        self.bytes += packet.bytes.len();
+        self.packets.push_back(packet);
+        while self.packets.len() > CAPACITY || (self.bytes > MAX_BYTES && self.packets.len() > 1) {
+            if let Some(oldest) = self.packets.pop_front() {
+                self.bytes -= oldest.bytes.len();
+            }
+        }
+    }
+}
+
+// A frame as it came off the wire (or went in through the session handle).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecentPacket {
+    pub direction: Direction,
+    pub header: u16,
+    pub name: Option<String>,
+    pub bytes: Vec<u8>,
+    pub injected: bool,
+}
+
+impl RecentPacket {
+    pub fn new(packet: &Packet, injected: bool) -> Self {
+        RecentPacket {
+            direction: packet.direction,
+            header: packet.header.unwrap_or_default(),
+            name: packet.name.clone(),
+            bytes: packet.bytes.clone(),
+            injected,
+        }
+    }
+
+    // Reads back a line as printed below, e.g. `[Out] [1066] Chat (injected) [0][0][0][2][4]*`.
+    // The frame is what counts, the header and name shown in front of it are ignored.
+    pub fn parse(line: &str) -> Option<RecentPacket> {
+        let (direction, rest) = line.trim().strip_prefix('[')?.split_once(']')?;
+        let direction = Direction::parse(direction)?;
+        let (_, rest) = rest.trim_start().strip_prefix('[')?.split_once(']')?;
+        let (_, rest) = rest.trim_start().split_once(' ')?;
+        let (injected, frame) = match rest.strip_prefix("(injected) ") {
+            Some(frame) => (true, frame),
+            None => (false, rest),
+        };
+
+        let packet = expression::parse_packet(frame, direction).ok()?;
+        Some(RecentPacket {
+            header: packet.get_header().ok()?,
+            ..RecentPacket::new(&packet, injected)
+        })
+    }
+}
+
+// The escaped frame can be pasted straight back into `send`.
+impl fmt::Display for RecentPacket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[{:<3}] [{}] {}{} {}",
+            self.direction,
+            self.header,
+            self.name.as_deref().unwrap_or("?"),
+            if self.injected { " (injected)" } else { "" },
+            expression::escape(&self.bytes)
+        )
+    }
+}
+
+pub fn record(packet: &Packet, injected: bool) {
+    RECENT
+        .lock()
+        .unwrap()
+        .push(RecentPacket::new(packet, injected));
+}
+
+// Oldest first.
+pub fn latest(count: usize) -> Vec<RecentPacket> {
+    let recent = &RECENT.lock().unwrap().packets;
+    recent
+        .iter()
+        .skip(recent.len().saturating_sub(count))
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet_handler::packet_builder::PacketBuilder;
+
+    fn frame(body: usize) -> RecentPacket {
+        let packet = PacketBuilder::new(1, Direction::In)
+            .append_bytes(&vec![0; body])
+            .build();
+        RecentPacket::new(&packet, false)
+    }
+
+    #[test]
+    fn bounded_by_count_and_bytes() {
+        let mut recent = RecentPackets::default();
+        for _ in 0..CAPACITY + 5 {
+            recent.push(frame(0));
+        }
+        assert_eq!(recent.packets.len(), CAPACITY);
+
+        for _ in 0..5 {
+            recent.push(frame(1024 * 1024));
+        }
+        assert!(recent.bytes <= MAX_BYTES);
+        assert_eq!(
+            recent.bytes,
+            recent.packets.iter().map(|p| p.bytes.len()).sum::<usize>()
+        );
+
+        recent.push(frame(MAX_BYTES));
+        assert_eq!(recent.packets.len(), 1);
+    }
+}
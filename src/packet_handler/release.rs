@@ -0,0 +1,100 @@
+use crate::packet_handler::packet::Packet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_RELEASE: &str = "MAC63-202307041149-55201637";
+
+// How long we hold off naming packets for the handshake to be looked at before naming them anyway.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(15);
+
+// Open once the definitions match the client's release. Until then frames are still forwarded
+// right away, they just aren't named or logged yet. Cloned into both forward tasks.
+#[derive(Debug, Clone)]
+pub struct ReleaseGate {
+    opened: Arc<AtomicBool>,
+    created: Instant,
+}
+
+impl ReleaseGate {
+    pub fn new(open: bool) -> Self {
+        ReleaseGate {
+            opened: Arc::new(AtomicBool::new(open)),
+            created: Instant::now(),
+        }
+    }
+
+    pub fn open(&self) {
+        self.opened.store(true, Ordering::SeqCst);
+    }
+
+    // if the handshake never shows up we carry on with what we have
+    pub fn is_open(&self) -> bool {
+        self.opened.load(Ordering::SeqCst) || self.created.elapsed() >= HANDSHAKE_TIMEOUT
+    }
+}
+
+// The client's first packet (ClientHello) starts with its release string,
+// e.g. MAC63-202307041149-55201637 or PRODUCTION-202307041149-55201637.
+pub fn detect_release(packet: &Packet) -> Option<String> {
+    let mut packet = packet.clone();
+    packet.reset();
+    let release = packet.read_string().ok()?;
+    looks_like_release(&release).then_some(release)
+}
+
+fn looks_like_release(value: &str) -> bool {
+    let parts = value.split('-').collect::<Vec<_>>();
+    parts.len() >= 3
+        && value.len() <= 64
+        && parts
+            .iter()
+            .all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric()))
+        && parts[1].len() == 12
+        && parts[1].chars().all(|c| c.is_ascii_digit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet_handler::direction::Direction;
+    use crate::packet_handler::packet_builder::PacketBuilder;
+
+    fn hello(release: &str) -> Packet {
+        PacketBuilder::new(4000, Direction::Out)
+            .append_string(release)
+            .append_string("FLASH")
+            .append_int(1)
+            .append_int(0)
+            .build()
+    }
+
+    #[test]
+    fn reads_the_release_from_client_hello() {
+        assert_eq!(
+            detect_release(&hello("PRODUCTION-202307041149-55201637")).as_deref(),
+            Some("PRODUCTION-202307041149-55201637")
+        );
+        assert_eq!(
+            detect_release(&hello(DEFAULT_RELEASE)).as_deref(),
+            Some(DEFAULT_RELEASE)
+        );
+    }
+
+    #[test]
+    fn ignores_packets_that_are_not_a_handshake() {
+        assert_eq!(detect_release(&hello("hello world")), None);
+        assert_eq!(detect_release(&hello("a-b-c")), None);
+        let packet = PacketBuilder::new(1, Direction::Out).append_int(5).build();
+        assert_eq!(detect_release(&packet), None);
+    }
+
+    #[test]
+    fn gate_stays_shut_until_opened() {
+        let gate = ReleaseGate::new(false);
+        let other_side = gate.clone();
+        assert!(!other_side.is_open());
+        gate.open();
+        assert!(other_side.is_open());
+    }
+}
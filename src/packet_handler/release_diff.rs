@@ -0,0 +1,127 @@
+use crate::packet_handler::direction::Direction;
+use crate::packet_handler::message_registry::{MessageInfo, MessageRegistry};
+use std::collections::BTreeMap;
+use std::fmt;
+
+// What changed between two releases' definitions, matched up by name. Header ids get shuffled
+// on most client updates, names mostly survive.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReleaseDiff {
+    pub added: Vec<MessageInfo>,
+    pub removed: Vec<MessageInfo>,
+    // (old, new): same name, different header
+    pub renumbered: Vec<(MessageInfo, MessageInfo)>,
+}
+
+impl ReleaseDiff {
+    pub fn between(old: &MessageRegistry, new: &MessageRegistry) -> ReleaseDiff {
+        let old_messages = by_name(old);
+        let new_messages = by_name(new);
+        let mut diff = ReleaseDiff::default();
+
+        for (key, old_message) in &old_messages {
+            match new_messages.get(key) {
+                None => diff.removed.push((*old_message).clone()),
+                Some(new_message) if new_message.header != old_message.header => diff
+                    .renumbered
+                    .push(((*old_message).clone(), (*new_message).clone())),
+                Some(_) => {}
+            }
+        }
+        for (key, new_message) in &new_messages {
+            if !old_messages.contains_key(key) {
+                diff.added.push((*new_message).clone());
+            }
+        }
+
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.renumbered.is_empty()
+    }
+}
+
+// sorted so the report reads the same every time
+fn by_name(registry: &MessageRegistry) -> BTreeMap<(Direction, &str), &MessageInfo> {
+    registry
+        .messages()
+        .map(|message| ((message.direction, message.name.as_str()), message))
+        .collect()
+}
+
+impl fmt::Display for ReleaseDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} added, {} removed, {} renumbered",
+            self.added.len(),
+            self.removed.len(),
+            self.renumbered.len()
+        )?;
+        for message in &self.added {
+            writeln!(
+                f,
+                "+ [{:<3}] {:>5} {}",
+                message.direction, message.header, message.name
+            )?;
+        }
+        for message in &self.removed {
+            writeln!(
+                f,
+                "- [{:<3}] {:>5} {}",
+                message.direction, message.header, message.name
+            )?;
+        }
+        for (old, new) in &self.renumbered {
+            writeln!(
+                f,
+                "~ [{:<3}] {:>5} -> {:<5} {}",
+                old.direction, old.header, new.header, old.name
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry(messages: &[(Direction, u16, &str)]) -> MessageRegistry {
+        let mut registry = MessageRegistry::new();
+        for (direction, header, name) in messages {
+            registry.insert(MessageInfo::new(*direction, *header, name));
+        }
+        registry
+    }
+
+    #[test]
+    fn reports_added_removed_and_renumbered() {
+        let old = registry(&[
+            (Direction::In, 1066, "Chat"),
+            (Direction::In, 10, "Gone"),
+            (Direction::Out, 5, "Ping"),
+        ]);
+        let new = registry(&[
+            (Direction::In, 2000, "Chat"),
+            (Direction::In, 10, "Fresh"),
+            (Direction::Out, 5, "Ping"),
+        ]);
+
+        let diff = ReleaseDiff::between(&old, &new);
+
+        assert_eq!(
+            diff.added,
+            vec![MessageInfo::new(Direction::In, 10, "Fresh")]
+        );
+        assert_eq!(
+            diff.removed,
+            vec![MessageInfo::new(Direction::In, 10, "Gone")]
+        );
+        assert_eq!(diff.renumbered.len(), 1);
+        assert_eq!(diff.renumbered[0].0.header, 1066);
+        assert_eq!(diff.renumbered[0].1.header, 2000);
+        assert!(ReleaseDiff::between(&old, &old).is_empty());
+    }
+}
@@ -0,0 +1,280 @@
+use crate::packet_handler::direction::Direction;
+use crate::packet_handler::packet::Packet;
+use crate::packet_handler::packet_builder::PacketBuilder;
+use crate::packet_handler::packet_handler::PacketHandler;
+use crate::packet_handler::packet_value::PacketValue;
+use crate::packet_handler::rules::glob_match;
+use regex::Regex;
+use serde_json::Value;
+
+#[derive(Debug, Clone)]
+pub enum Edit {
+    // every match in a string field
+    Replace { pattern: Regex, with: String },
+    // numeric fields, either end can be left open
+    Clamp { min: Option<i64>, max: Option<i64> },
+    // must be the field's own type, anything else leaves the field alone
+    Set(Value),
+}
+
+// Changes one decoded field of a message. Lives in the rules file next to the drop rules:
+//
+//   {"rewrites": [
+//     {"name": "Chat", "direction": "out", "field": 0, "replace": {"pattern": "(?i)darn", "with": "***"}},
+//     {"name": "Chat", "field": 1, "clamp": {"min": 0, "max": 20}},
+//     {"name": "Door*", "field": 2, "set": true}
+//   ]}
+#[derive(Debug, Clone)]
+pub struct Rewrite {
+    pub direction: Option<Direction>,
+    // * and ? wildcards, like the drop rules
+    pub name: String,
+    // position in the decoded body, group counts included. A count itself is never rewritten,
+    // changing it would leave the body disagreeing with its own structure.
+    pub field: usize,
+    pub edit: Edit,
+}
+
+impl Rewrite {
+    pub fn parse(entry: &Value) -> Result<Rewrite, String> {
+        let name = entry
+            .get("name")
+            .and_then(|v| v.as_str())
+            .ok_or("a rewrite needs a message \"name\"")?;
+        let field = entry
+            .get("field")
+            .and_then(|v| v.as_u64())
+            .ok_or("a rewrite needs a \"field\" index")? as usize;
+        let direction = match entry.get("direction").and_then(|v| v.as_str()) {
+            None => None,
+            Some(direction) => Some(
+                Direction::parse(direction)
+                    .ok_or_else(|| format!("unknown direction {}", direction))?,
+            ),
+        };
+
+        let edit = if let Some(replace) = entry.get("replace") {
+            let pattern = replace
+                .get("pattern")
+                .and_then(|v| v.as_str())
+                .ok_or("\"replace\" needs a \"pattern\"")?;
+            Edit::Replace {
+                pattern: Regex::new(pattern).map_err(|e| e.to_string())?,
+                with: replace
+                    .get("with")
+                    .and_then(|v| v.as_str())
+                    .unwrap_or_default()
+                    .to_owned(),
+            }
+        } else if let Some(clamp) = entry.get("clamp") {
+            Edit::Clamp {
+                min: clamp.get("min").and_then(|v| v.as_i64()),
+                max: clamp.get("max").and_then(|v| v.as_i64()),
+            }
+        } else if let Some(value) = entry.get("set") {
+            Edit::Set(value.clone())
+        } else {
+            return Err("a rewrite needs one of \"replace\", \"clamp\" or \"set\"".to_owned());
+        };
+
+        Ok(Rewrite {
+            direction,
+            name: name.to_owned(),
+            field,
+            edit,
+        })
+    }
+
+    pub fn applies_to(&self, packet: &Packet) -> bool {
+        self.direction
+            .is_none_or(|direction| direction == packet.direction)
+            && packet
+                .name
+                .as_ref()
+                .is_some_and(|name| glob_match(&self.name, name))
+    }
+
+    // The value after the edit, None when there's nothing to change.
+    fn edit(&self, value: &PacketValue) -> Option<PacketValue> {
+        let edited = match (&self.edit, value) {
+            (Edit::Replace { pattern, with }, PacketValue::String(text)) => {
+                PacketValue::String(pattern.replace_all(text, with.as_str()).into_owned())
+            }
+            (Edit::Clamp { min, max }, value) => {
+                let clamp = |number: i64| {
+                    let number = min.map_or(number, |min| number.max(min));
+                    max.map_or(number, |max| number.min(max))
+                };
+                match value {
+                    PacketValue::Int(number) => PacketValue::Int(
+                        clamp(*number as i64).clamp(i32::MIN as i64, i32::MAX as i64) as i32,
+                    ),
+                    PacketValue::Short(number) => {
+                        PacketValue::Short(clamp(*number as i64).clamp(0, u16::MAX as i64) as u16)
+                    }
+                    PacketValue::Long(number) => PacketValue::Long(clamp(*number)),
+                    PacketValue::Byte(number) => {
+                        PacketValue::Byte(clamp(*number as i64).clamp(0, u8::MAX as i64) as u8)
+                    }
+                    _ => return None,
+                }
+            }
+            (Edit::Set(Value::String(text)), PacketValue::String(_)) => {
+                PacketValue::String(text.clone())
+            }
+            (Edit::Set(Value::Bool(flag)), PacketValue::Bool(_)) => PacketValue::Bool(*flag),
+            (Edit::Set(Value::Number(number)), value) => {
+                let number = number.as_i64()?;
+                match value {
+                    PacketValue::Int(_) => PacketValue::Int(i32::try_from(number).ok()?),
+                    PacketValue::Short(_) => PacketValue::Short(u16::try_from(number).ok()?),
+                    PacketValue::Long(_) => PacketValue::Long(number),
+                    PacketValue::Byte(_) => PacketValue::Byte(u8::try_from(number).ok()?),
+                    _ => return None,
+                }
+            }
+            _ => return None,
+        };
+        (edited != *value).then_some(edited)
+    }
+}
+
+// Runs every rewrite that applies, in order, and re-encodes the frame if any of them changed
+// something. The length prefix is worked out again by the builder. None means leave the frame as it is,
+// which is also what happens to messages without a structure or with a body it doesn't fit exactly.
+pub fn apply(rewrites: &[Rewrite], packet: &Packet) -> Option<Packet> {
+    let rewrites: Vec<&Rewrite> = rewrites
+        .iter()
+        .filter(|rewrite| rewrite.applies_to(packet))
+        .collect();
+    if rewrites.is_empty() {
+        return None;
+    }
+
+    let structure = PacketHandler::get_structure(packet)?;
+    if !structure.fits(&mut packet.clone()) {
+        return None;
+    }
+    let (mut values, counts) = structure.decode_with_counts(&mut packet.clone()).ok()?;
+    let mut changed = false;
+    for rewrite in rewrites {
+        if counts.contains(&rewrite.field) {
+            continue;
+        }
+        let Some(value) = values.get_mut(rewrite.field) else {
+            continue;
+        };
+        if let Some(edited) = rewrite.edit(value) {
+            *value = edited;
+            changed = true;
+        }
+    }
+    if !changed {
+        return None;
+    }
+
+    let mut rewritten = values
+        .iter()
+        .fold(
+            PacketBuilder::new(packet.get_header().ok()?, packet.direction),
+            |builder, value| builder.append_value(value),
+        )
+        .build();
+    rewritten.name = packet.name.clone();
+    Some(rewritten)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet_handler::structure::Structure;
+    use crate::packet_handler::test_support;
+    use serde_json::json;
+
+    fn chat(text: &str, bubble: i32, typing: bool) -> Packet {
+        test_support::message(
+            "RewriteTestChat",
+            "siB",
+            PacketBuilder::new(7, Direction::Out)
+                .append_string(text)
+                .append_int(bubble)
+                .append_bool(typing),
+        )
+    }
+
+    fn rewrites(entries: Value) -> Vec<Rewrite> {
+        entries
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|entry| Rewrite::parse(entry).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn rewrites_fields_and_fixes_the_length() {
+        let rewrites = rewrites(json!([
+            {"name": "RewriteTest*", "field": 0, "replace": {"pattern": "(?i)darn", "with": "****"}},
+            {"name": "RewriteTestChat", "field": 1, "clamp": {"min": 0, "max": 20}},
+            {"name": "RewriteTestChat", "direction": "out", "field": 2, "set": false}
+        ]));
+
+        let rewritten = apply(&rewrites, &chat("oh Darn it", 35, true)).unwrap();
+
+        assert_eq!(rewritten, chat("oh **** it", 20, false));
+        assert!(rewritten.get_body().is_ok());
+    }
+
+    #[test]
+    fn leaves_frames_alone_when_nothing_changes() {
+        let rewrites = rewrites(json!([
+            {"name": "RewriteTestChat", "field": 0, "replace": {"pattern": "darn", "with": "****"}},
+            {"name": "RewriteTestChat", "field": 1, "set": "not an int"},
+            {"name": "RewriteTestChat", "direction": "in", "field": 2, "set": false},
+            {"name": "RewriteTestChat", "field": 9, "set": 1}
+        ]));
+
+        assert!(apply(&rewrites, &chat("hello", 3, true)).is_none());
+    }
+
+    #[test]
+    fn leaves_counts_and_ill_fitting_bodies_alone() {
+        let list = |extra: &[u8]| {
+            test_support::message(
+                "RewriteTestList",
+                "{s}",
+                PacketBuilder::new(8, Direction::Out)
+                    .append_int(2)
+                    .append_string("a")
+                    .append_string("b")
+                    .append_bytes(extra),
+            )
+        };
+        let rewrites = rewrites(json!([
+            {"name": "RewriteTestList", "field": 0, "set": 1},
+            {"name": "RewriteTestList", "field": 2, "set": "c"}
+        ]));
+
+        let rewritten = apply(&rewrites, &list(&[])).unwrap();
+        let structure = Structure::parse("{s}").unwrap();
+        assert_eq!(
+            structure.decode(&mut rewritten.clone()).unwrap(),
+            vec![
+                PacketValue::Int(2),
+                PacketValue::String("a".to_owned()),
+                PacketValue::String("c".to_owned())
+            ]
+        );
+        assert!(apply(&rewrites, &list(&[0])).is_none());
+    }
+
+    #[test]
+    fn rejects_bad_rewrites() {
+        assert!(Rewrite::parse(&json!({"name": "Chat", "field": 0})).is_err());
+        assert!(
+            Rewrite::parse(&json!({"name": "Chat", "field": 0, "replace": {"pattern": "("}}))
+                .is_err()
+        );
+        assert!(Rewrite::parse(&json!({"field": 0, "set": 1})).is_err());
+    }
+}
@@ -0,0 +1,422 @@
+use crate::logger::ConsoleLogger;
+use crate::packet_handler::definitions::DefinitionError;
+use crate::packet_handler::direction::Direction;
+use crate::packet_handler::interceptor::{PacketInterceptor, Verdict};
+use crate::packet_handler::message_source;
+use crate::packet_handler::packet::Packet;
+use crate::packet_handler::packet_handler::PacketHandler;
+use crate::packet_handler::packet_value::PacketValue;
+use crate::packet_handler::rewrite::{self, Rewrite};
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+// how often the rules file is checked for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Drop,
+    Pass,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldMatch {
+    // position in the decoded body, group counts included
+    pub index: usize,
+    pub equals: Value,
+}
+
+// Every condition that's given has to hold. A rule with none matches everything.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    pub action: Action,
+    pub direction: Option<Direction>,
+    pub header: Option<u16>,
+    // * and ? wildcards
+    pub name: Option<String>,
+    pub field: Option<FieldMatch>,
+}
+
+impl Rule {
+    pub fn matches(&self, packet: &Packet) -> bool {
+        if self
+            .direction
+            .is_some_and(|direction| direction != packet.direction)
+        {
+            return false;
+        }
+        if self.header.is_some() && self.header != packet.get_header().ok() {
+            return false;
+        }
+        if let Some(pattern) = &self.name {
+            if !packet
+                .name
+                .as_ref()
+                .is_some_and(|name| glob_match(pattern, name))
+            {
+                return false;
+            }
+        }
+        match &self.field {
+            Some(field) => field_matches(packet, field),
+            None => true,
+        }
+    }
+}
+
+// A rules file, JSON or TOML. The first rule that matches decides, frames no rule matches pass.
+// Frames that pass then go through the rewrites, see `rewrite.rs`.
+//
+//   {"rules": [
+//     {"action": "pass", "direction": "out", "name": "Chat", "field": {"index": 0, "equals": "hi"}},
+//     {"action": "drop", "direction": "out", "name": "Track*"},
+//     {"action": "drop", "header": 4000}
+//   ],
+//   "rewrites": [...]}
+#[derive(Debug, Clone, Default)]
+pub struct Rules {
+    rules: Vec<Rule>,
+    rewrites: Vec<Rewrite>,
+}
+
+impl Rules {
+    pub fn load(path: &Path) -> Result<Rules, DefinitionError> {
+        Self::parse(&message_source::read_json_or_toml(path)?)
+    }
+
+    pub fn parse(json: &Value) -> Result<Rules, DefinitionError> {
+        Ok(Rules {
+            rules: parse_list(json, "rules", "rule", parse_rule)?,
+            rewrites: parse_list(json, "rewrites", "rewrite", Rewrite::parse)?,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.rules.len() + self.rewrites.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // The rewritten frame, if any rewrite changed something.
+    pub fn rewrite(&self, packet: &Packet) -> Option<Packet> {
+        rewrite::apply(&self.rewrites, packet)
+    }
+
+    pub fn action(&self, packet: &Packet) -> Action {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(packet))
+            .map_or(Action::Pass, |rule| rule.action)
+    }
+}
+
+// Either list can be left out.
+fn parse_list<T>(
+    json: &Value,
+    key: &str,
+    what: &str,
+    parse: fn(&Value) -> Result<T, String>,
+) -> Result<Vec<T>, DefinitionError> {
+    let Some(entries) = json.get(key) else {
+        return Ok(Vec::new());
+    };
+    let entries = entries
+        .as_array()
+        .ok_or_else(|| DefinitionError::Schema(format!("\"{}\" must be a list", key)))?;
+
+    entries
+        .iter()
+        .enumerate()
+        .map(|(index, entry)| {
+            parse(entry).map_err(|reason| {
+                DefinitionError::Schema(format!("{} {}: {}", what, index + 1, reason))
+            })
+        })
+        .collect()
+}
+
+fn parse_rule(entry: &Value) -> Result<Rule, String> {
+    let action = match entry.get("action").and_then(|v| v.as_str()) {
+        Some("drop") => Action::Drop,
+        Some("pass") => Action::Pass,
+        _ => return Err("\"action\" must be drop or pass".to_owned()),
+    };
+    let direction = match entry.get("direction").and_then(|v| v.as_str()) {
+        None => None,
+        Some(direction) => Some(
+            Direction::parse(direction)
+                .ok_or_else(|| format!("unknown direction {}", direction))?,
+        ),
+    };
+    let header = match entry.get("header") {
+        None => None,
+        Some(header) => Some(
+            header
+                .as_u64()
+                .filter(|header| *header <= u16::MAX as u64)
+                .ok_or_else(|| format!("bad header {}", header))? as u16,
+        ),
+    };
+    let field = match entry.get("field") {
+        None => None,
+        Some(field) => Some(FieldMatch {
+            index: field
+                .get("index")
+                .and_then(|v| v.as_u64())
+                .ok_or("\"field\" needs an \"index\"")? as usize,
+            equals: field
+                .get("equals")
+                .cloned()
+                .ok_or("\"field\" needs an \"equals\"")?,
+        }),
+    };
+
+    Ok(Rule {
+        action,
+        direction,
+        header,
+        name: entry
+            .get("name")
+            .and_then(|v| v.as_str())
+            .map(str::to_owned),
+        field,
+    })
+}
+
+// Only messages with a known structure can be matched on a field, anything else doesn't match.
+fn field_matches(packet: &Packet, field: &FieldMatch) -> bool {
+    let Some(structure) = PacketHandler::get_structure(packet) else {
+        return false;
+    };
+    let Ok(values) = structure.decode(&mut packet.clone()) else {
+        return false;
+    };
+
+    match (values.get(field.index), &field.equals) {
+        (Some(PacketValue::String(value)), Value::String(expected)) => value == expected,
+        (Some(PacketValue::Bool(value)), Value::Bool(expected)) => value == expected,
+        (Some(PacketValue::Int(value)), Value::Number(expected)) => {
+            expected.as_i64() == Some(*value as i64)
+        }
+        (Some(PacketValue::Short(value)), Value::Number(expected)) => {
+            expected.as_i64() == Some(*value as i64)
+        }
+        (Some(PacketValue::Long(value)), Value::Number(expected)) => {
+            expected.as_i64() == Some(*value)
+        }
+        (Some(PacketValue::Byte(value)), Value::Number(expected)) => {
+            expected.as_i64() == Some(*value as i64)
+        }
+        _ => false,
+    }
+}
+
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // where the last * was, and how much of the text it has swallowed so far
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, swallowed)) => {
+                    p = star + 1;
+                    t = swallowed + 1;
+                    backtrack = Some((star, swallowed + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+// The rules file as an interceptor. Edits are picked up on the fly, a broken edit keeps the old rules.
+pub struct RulesInterceptor {
+    path: PathBuf,
+    rules: Rules,
+    // modification time and size of what's loaded, None when there's no file
+    loaded: Option<(SystemTime, u64)>,
+    last_checked: Instant,
+}
+
+impl RulesInterceptor {
+    pub fn new(path: &str) -> Self {
+        let mut interceptor = RulesInterceptor {
+            path: PathBuf::from(path),
+            rules: Rules::default(),
+            loaded: None,
+            last_checked: Instant::now(),
+        };
+        interceptor.reload_if_changed();
+        interceptor
+    }
+
+    pub fn reload_if_changed(&mut self) {
+        self.last_checked = Instant::now();
+        let stamp = std::fs::metadata(&self.path)
+            .ok()
+            .and_then(|metadata| Some((metadata.modified().ok()?, metadata.len())));
+        if stamp == self.loaded {
+            return;
+        }
+        self.loaded = stamp;
+
+        if stamp.is_none() {
+            if !self.rules.is_empty() {
+                ConsoleLogger::normal(format!(
+                    "{} is gone, dropping its rules",
+                    self.path.display()
+                ));
+            }
+            self.rules = Rules::default();
+            return;
+        }
+        match Rules::load(&self.path) {
+            Ok(rules) => {
+                ConsoleLogger::normal(format!(
+                    "Loaded {} rules from {}",
+                    rules.len(),
+                    self.path.display()
+                ));
+                self.rules = rules;
+            }
+            Err(e) => ConsoleLogger::warning(format!(
+                "Keeping the previous rules, could not load {}: {}",
+                self.path.display(),
+                e
+            )),
+        }
+    }
+}
+
+impl PacketInterceptor for RulesInterceptor {
+    fn name(&self) -> String {
+        format!("rules ({})", self.path.display())
+    }
+
+    fn intercept(&mut self, packet: &Packet) -> Verdict {
+        if self.last_checked.elapsed() >= RELOAD_INTERVAL {
+            self.reload_if_changed();
+        }
+        match self.rules.action(packet) {
+            Action::Drop => Verdict::Drop,
+            Action::Pass => match self.rules.rewrite(packet) {
+                Some(rewritten) => Verdict::Replace(vec![rewritten]),
+                None => Verdict::Forward,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet_handler::packet_builder::PacketBuilder;
+    use crate::packet_handler::test_support;
+    use serde_json::json;
+
+    fn frame(header: u16, direction: Direction) -> PacketBuilder {
+        PacketBuilder::new(header, direction).append_string("")
+    }
+
+    fn packet(builder: PacketBuilder, name: &str) -> Packet {
+        let mut packet = builder.build();
+        packet.name = Some(name.to_owned());
+        packet
+    }
+
+    #[test]
+    fn globs() {
+        assert!(glob_match("Track*", "TrackingEvent"));
+        assert!(glob_match("*Event", "TrackingEvent"));
+        assert!(glob_match("T?ack*Ev*t", "TrackingEvent"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("Track*", "Chat"));
+        assert!(!glob_match("Chat?", "Chat"));
+    }
+
+    #[test]
+    fn first_matching_rule_decides() {
+        let rules = Rules::parse(&json!({"rules": [
+            {"action": "pass", "direction": "out", "header": 7},
+            {"action": "drop", "direction": "out", "name": "Track*"},
+            {"action": "drop", "header": 9}
+        ]}))
+        .unwrap();
+
+        let tracking = |header| packet(frame(header, Direction::Out), "TrackingEvent");
+        assert_eq!(rules.action(&tracking(1)), Action::Drop);
+        assert_eq!(rules.action(&tracking(7)), Action::Pass);
+        let incoming = packet(frame(1, Direction::In), "TrackingEvent");
+        assert_eq!(rules.action(&incoming), Action::Pass);
+        let by_header = packet(frame(9, Direction::In), "Other");
+        assert_eq!(rules.action(&by_header), Action::Drop);
+    }
+
+    #[test]
+    fn matches_decoded_fields() {
+        let rules = Rules::parse(&json!({"rules": [
+            {"action": "drop", "name": "RulesTestChat", "field": {"index": 0, "equals": "spam"}},
+            {"action": "drop", "name": "RulesTestChat", "field": {"index": 1, "equals": 3}}
+        ]}))
+        .unwrap();
+        let chat = |text: &str, color: i32| {
+            test_support::message(
+                "RulesTestChat",
+                "si",
+                PacketBuilder::new(1, Direction::Out)
+                    .append_string(text)
+                    .append_int(color),
+            )
+        };
+
+        assert_eq!(rules.action(&chat("spam", 0)), Action::Drop);
+        assert_eq!(rules.action(&chat("hi", 3)), Action::Drop);
+        assert_eq!(rules.action(&chat("hi", 0)), Action::Pass);
+    }
+
+    #[test]
+    fn rejects_bad_rules() {
+        assert!(Rules::parse(&json!({"rules": [{"action": "block"}]})).is_err());
+        assert!(Rules::parse(&json!({"rules": [{"action": "drop", "direction": "up"}]})).is_err());
+        assert!(
+            Rules::parse(&json!({"rules": [{"action": "drop", "field": {"index": 0}}]})).is_err()
+        );
+    }
+
+    #[test]
+    fn reloads_when_the_file_changes() {
+        let path = std::env::temp_dir().join(format!("hablog-rules-{}.json", std::process::id()));
+        std::fs::write(&path, r#"{"rules": []}"#).unwrap();
+        let mut interceptor = RulesInterceptor::new(path.to_str().unwrap());
+        let ping = packet(frame(5, Direction::Out), "Ping");
+        assert_eq!(interceptor.intercept(&ping), Verdict::Forward);
+
+        std::fs::write(&path, r#"{"rules": [{"action": "drop", "name": "Ping"}]}"#).unwrap();
+        interceptor.reload_if_changed();
+        assert_eq!(interceptor.intercept(&ping), Verdict::Drop);
+
+        // a broken edit keeps what we had
+        std::fs::write(&path, r#"{"rules": [{"action": "#).unwrap();
+        interceptor.reload_if_changed();
+        assert_eq!(interceptor.intercept(&ping), Verdict::Drop);
+
+        std::fs::remove_file(&path).unwrap();
+        interceptor.reload_if_changed();
+        assert_eq!(interceptor.intercept(&ping), Verdict::Forward);
+    }
+}
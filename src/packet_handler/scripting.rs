@@ -0,0 +1,404 @@
+use crate::logger::ConsoleLogger;
+use crate::packet_handler::direction::Direction;
+use crate::packet_handler::expression;
+use crate::packet_handler::interceptor::{PacketInterceptor, Verdict};
+use crate::packet_handler::packet::{Packet, BODY_OFFSET};
+use crate::packet_handler::packet_builder::PacketBuilder;
+use crate::packet_handler::packet_handler::PacketHandler;
+use crate::packet_handler::packet_value::PacketValue;
+use crate::session::SessionHandle;
+use rhai::{Array, Blob, CallFnOptions, Dynamic, Engine, EvalAltResult, Map, Scope, AST};
+use std::path::Path;
+
+// plenty for any sane handler, and a runaway loop can't hold up the proxy for long
+const MAX_OPERATIONS: u64 = 1_000_000;
+
+// What scripts see of a packet. Bodies come decoded when the message has a structure,
+// otherwise as a single blob.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScriptPacket {
+    direction: Direction,
+    header: u16,
+    name: Option<String>,
+    values: Vec<PacketValue>,
+    decoded: bool,
+}
+
+impl ScriptPacket {
+    pub fn from_packet(packet: &Packet) -> Option<Self> {
+        let header = packet.get_header().ok()?;
+        let decoded = PacketHandler::get_structure(packet)
+            .and_then(|structure| structure.decode(&mut packet.clone()).ok());
+
+        Some(ScriptPacket {
+            direction: packet.direction,
+            header,
+            name: packet.name.clone(),
+            decoded: decoded.is_some(),
+            values: decoded.unwrap_or_else(|| {
+                vec![PacketValue::Bytes(
+                    packet.bytes.get(BODY_OFFSET..).unwrap_or_default().to_vec(),
+                )]
+            }),
+        })
+    }
+
+    pub fn to_packet(&self) -> Packet {
+        let mut packet = self
+            .values
+            .iter()
+            .fold(
+                PacketBuilder::new(self.header, self.direction),
+                |builder, value| builder.append_value(value),
+            )
+            .build();
+        packet.name = self.name.clone();
+        packet
+    }
+
+    fn get(&mut self, index: i64) -> Result<Dynamic, Box<EvalAltResult>> {
+        let value = usize::try_from(index)
+            .ok()
+            .and_then(|index| self.values.get(index))
+            .ok_or_else(|| format!("no field {} in {}", index, self.describe()))?;
+        Ok(to_dynamic(value))
+    }
+
+    // The new value has to fit the field's type, so the frame stays what the other end expects.
+    fn set(&mut self, index: i64, value: Dynamic) -> Result<(), Box<EvalAltResult>> {
+        let description = self.describe();
+        let field = usize::try_from(index)
+            .ok()
+            .and_then(|index| self.values.get_mut(index))
+            .ok_or_else(|| format!("no field {} in {}", index, description))?;
+        *field = from_dynamic(field, value)
+            .ok_or_else(|| format!("field {} of {} can't hold that value", index, description))?;
+        Ok(())
+    }
+
+    fn describe(&self) -> String {
+        self.name.clone().unwrap_or_else(|| self.header.to_string())
+    }
+}
+
+fn to_dynamic(value: &PacketValue) -> Dynamic {
+    match value {
+        PacketValue::Int(value) => Dynamic::from_int(*value as i64),
+        PacketValue::Short(value) => Dynamic::from_int(*value as i64),
+        PacketValue::Long(value) => Dynamic::from_int(*value),
+        PacketValue::Byte(value) => Dynamic::from_int(*value as i64),
+        PacketValue::Bool(value) => Dynamic::from_bool(*value),
+        PacketValue::String(value) => Dynamic::from(value.clone()),
+        PacketValue::Bytes(value) => Dynamic::from_blob(value.clone()),
+    }
+}
+
+fn from_dynamic(field: &PacketValue, value: Dynamic) -> Option<PacketValue> {
+    Some(match field {
+        PacketValue::Int(_) => PacketValue::Int(i32::try_from(value.as_int().ok()?).ok()?),
+        PacketValue::Short(_) => PacketValue::Short(u16::try_from(value.as_int().ok()?).ok()?),
+        PacketValue::Long(_) => PacketValue::Long(value.as_int().ok()?),
+        PacketValue::Byte(_) => PacketValue::Byte(u8::try_from(value.as_int().ok()?).ok()?),
+        PacketValue::Bool(_) => PacketValue::Bool(value.as_bool().ok()?),
+        PacketValue::String(_) => PacketValue::String(value.into_string().ok()?),
+        PacketValue::Bytes(_) => PacketValue::Bytes(value.try_cast::<Blob>()?),
+    })
+}
+
+// One script file. It's run once when loaded, then its `on_packet(packet)` sees every frame:
+//
+//   fn init() { #{ seen: 0 } }
+//
+//   fn on_packet(packet) {
+//       this.seen += 1;
+//       if packet.name == "Chat" && packet.get(0) == "ping" {
+//           inject("{in:Chat}{i:0}{s:\"pong\"}{i:0}{i:0}{i:0}");
+//           return false;
+//       }
+//   }
+//
+// Returning nothing (or true) forwards the packet, false drops it, and a packet or an array of
+// packets is sent instead. `this` is the script's own state, kept between packets; `init()` returns
+// its first value, an empty map by default.
+pub struct ScriptInterceptor {
+    name: String,
+    engine: Engine,
+    ast: AST,
+    scope: Scope<'static>,
+    state: Dynamic,
+}
+
+impl ScriptInterceptor {
+    pub fn load(path: &Path, session: SessionHandle) -> Result<Self, String> {
+        let source = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        Self::from_source(&path.display().to_string(), &source, session)
+    }
+
+    pub fn from_source(name: &str, source: &str, session: SessionHandle) -> Result<Self, String> {
+        let engine = Self::engine(name, session);
+        let ast = engine.compile(source).map_err(|e| e.to_string())?;
+        let mut scope = Scope::new();
+        engine
+            .run_ast_with_scope(&mut scope, &ast)
+            .map_err(|e| e.to_string())?;
+
+        let state = if ast.iter_functions().any(|f| f.name == "init") {
+            engine
+                .call_fn_with_options::<Dynamic>(
+                    CallFnOptions::new().eval_ast(false),
+                    &mut scope,
+                    &ast,
+                    "init",
+                    (),
+                )
+                .map_err(|e| e.to_string())?
+        } else {
+            Dynamic::from_map(Map::new())
+        };
+
+        Ok(ScriptInterceptor {
+            name: name.to_owned(),
+            engine,
+            ast,
+            scope,
+            state,
+        })
+    }
+
+    fn engine(name: &str, session: SessionHandle) -> Engine {
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+
+        let script = name.to_owned();
+        engine.on_print(move |text| ConsoleLogger::normal(format!("[{}] {}", script, text)));
+
+        engine
+            .register_type_with_name::<ScriptPacket>("Packet")
+            .register_get("direction", |packet: &mut ScriptPacket| {
+                packet.direction.to_string().to_lowercase()
+            })
+            .register_get("header", |packet: &mut ScriptPacket| packet.header as i64)
+            .register_get("name", |packet: &mut ScriptPacket| match &packet.name {
+                Some(name) => Dynamic::from(name.clone()),
+                None => Dynamic::UNIT,
+            })
+            .register_get("decoded", |packet: &mut ScriptPacket| packet.decoded)
+            .register_get("values", |packet: &mut ScriptPacket| {
+                packet.values.iter().map(to_dynamic).collect::<Array>()
+            })
+            .register_fn("len", |packet: &mut ScriptPacket| {
+                packet.values.len() as i64
+            })
+            .register_fn("get", ScriptPacket::get)
+            .register_fn("set", ScriptPacket::set)
+            .register_fn("to_string", |packet: &mut ScriptPacket| {
+                expression::escape(&packet.to_packet().bytes)
+            })
+            .register_fn("new_packet", |input: &str| {
+                let packet =
+                    expression::parse_packet(input, Direction::Out).map_err(|e| e.to_string())?;
+                ScriptPacket::from_packet(&packet)
+                    .ok_or_else(|| Box::<EvalAltResult>::from("not a valid frame"))
+            });
+
+        let expression_session = session.clone();
+        engine.register_fn(
+            "inject",
+            move |input: &str| -> Result<(), Box<EvalAltResult>> {
+                let packet =
+                    expression::parse_packet(input, Direction::Out).map_err(|e| e.to_string())?;
+                expression_session
+                    .send(packet)
+                    .map_err(|e| e.to_string().into())
+            },
+        );
+        engine.register_fn(
+            "inject",
+            move |packet: ScriptPacket| -> Result<(), Box<EvalAltResult>> {
+                session
+                    .send(packet.to_packet())
+                    .map_err(|e| e.to_string().into())
+            },
+        );
+
+        engine
+    }
+
+    fn verdict(&self, original: &ScriptPacket, result: Dynamic) -> Verdict {
+        if result.is_unit() || result.as_bool() == Ok(true) {
+            return Verdict::Forward;
+        }
+        if result.as_bool() == Ok(false) {
+            return Verdict::Drop;
+        }
+        if let Some(packet) = result.clone().try_cast::<ScriptPacket>() {
+            if packet == *original {
+                return Verdict::Forward;
+            }
+            return Verdict::Replace(vec![packet.to_packet()]);
+        }
+        if let Some(packets) = result.clone().try_cast::<Array>() {
+            let packets: Option<Vec<Packet>> = packets
+                .into_iter()
+                .map(|packet| Some(packet.try_cast::<ScriptPacket>()?.to_packet()))
+                .collect();
+            if let Some(packets) = packets {
+                return Verdict::Replace(packets);
+            }
+        }
+
+        ConsoleLogger::warning(format!(
+            "{} returned a {} from on_packet, forwarding as is",
+            self.name,
+            result.type_name()
+        ));
+        Verdict::Forward
+    }
+}
+
+impl PacketInterceptor for ScriptInterceptor {
+    fn name(&self) -> String {
+        format!("script ({})", self.name)
+    }
+
+    // A script that errors doesn't get to affect the packet.
+    fn intercept(&mut self, packet: &Packet) -> Verdict {
+        let Some(script_packet) = ScriptPacket::from_packet(packet) else {
+            return Verdict::Forward;
+        };
+
+        let result = self.engine.call_fn_with_options::<Dynamic>(
+            CallFnOptions::new()
+                .eval_ast(false)
+                .bind_this_ptr(&mut self.state),
+            &mut self.scope,
+            &self.ast,
+            "on_packet",
+            (script_packet.clone(),),
+        );
+        match result {
+            Ok(result) => self.verdict(&script_packet, result),
+            Err(e) => {
+                ConsoleLogger::warning(format!("{} failed: {}", self.name, e));
+                Verdict::Forward
+            }
+        }
+    }
+}
+
+// Every *.rhai file in the directory, in name order. Scripts that don't compile are skipped,
+// and so are ones without an on_packet.
+pub fn load_scripts(directory: &str, session: &SessionHandle) -> Vec<ScriptInterceptor> {
+    let Ok(entries) = std::fs::read_dir(directory) else {
+        return Vec::new();
+    };
+    let mut paths: Vec<_> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().and_then(|extension| extension.to_str()) == Some("rhai"))
+        .collect();
+    paths.sort();
+
+    let mut scripts = Vec::new();
+    for path in paths {
+        match ScriptInterceptor::load(&path, session.clone()) {
+            Ok(script) if script.ast.iter_functions().any(|f| f.name == "on_packet") => {
+                ConsoleLogger::normal(format!("Loaded script {}", path.display()));
+                scripts.push(script);
+            }
+            Ok(_) => ConsoleLogger::warning(format!(
+                "{} has no on_packet(packet), skipping it",
+                path.display()
+            )),
+            Err(e) => ConsoleLogger::warning(format!("Could not load {}: {}", path.display(), e)),
+        }
+    }
+    scripts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet_handler::test_support;
+
+    fn chat(text: &str) -> Packet {
+        test_support::message(
+            "ScriptTestChat",
+            "si",
+            PacketBuilder::new(7, Direction::Out)
+                .append_string(text)
+                .append_int(0),
+        )
+    }
+
+    fn script(source: &str) -> ScriptInterceptor {
+        ScriptInterceptor::from_source("test", source, SessionHandle::new()).unwrap()
+    }
+
+    #[test]
+    fn drops_and_rewrites() {
+        let mut interceptor = script(
+            r#"
+            fn on_packet(packet) {
+                if packet.get(0) == "drop me" { return false; }
+                if packet.get(0) == "shout" { packet.set(0, "SHOUT"); return packet; }
+            }
+            "#,
+        );
+
+        assert_eq!(interceptor.intercept(&chat("drop me")), Verdict::Drop);
+        assert_eq!(interceptor.intercept(&chat("hello")), Verdict::Forward);
+        assert_eq!(
+            interceptor.intercept(&chat("shout")),
+            Verdict::Replace(vec![chat("SHOUT")])
+        );
+    }
+
+    #[test]
+    fn keeps_state_between_packets() {
+        let mut interceptor = script(
+            r#"
+            fn init() { #{ seen: 0 } }
+            fn on_packet(packet) {
+                this.seen += 1;
+                if this.seen > 2 { return false; }
+            }
+            "#,
+        );
+
+        assert_eq!(interceptor.intercept(&chat("a")), Verdict::Forward);
+        assert_eq!(interceptor.intercept(&chat("b")), Verdict::Forward);
+        assert_eq!(interceptor.intercept(&chat("c")), Verdict::Drop);
+    }
+
+    #[test]
+    fn injects_through_the_session() {
+        let session = SessionHandle::new();
+        let mut injections = session.attach();
+        let mut interceptor = ScriptInterceptor::from_source(
+            "test",
+            r#"fn on_packet(packet) { inject("{in:9}{s:\"pong\"}"); [packet, new_packet("{out:8}")] }"#,
+            session,
+        )
+        .unwrap();
+
+        let Verdict::Replace(packets) = interceptor.intercept(&chat("ping")) else {
+            panic!("expected a replacement");
+        };
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[1].get_header(), Ok(8));
+        assert_eq!(injections.to_client.try_recv().unwrap().get_header(), Ok(9));
+    }
+
+    #[test]
+    fn broken_scripts_leave_packets_alone() {
+        let mut interceptor = script("fn on_packet(packet) { packet.set(5, 1); loop {} }");
+        assert_eq!(interceptor.intercept(&chat("x")), Verdict::Forward);
+
+        let mut runaway = script("fn on_packet(packet) { loop {} }");
+        assert_eq!(runaway.intercept(&chat("x")), Verdict::Forward);
+
+        assert!(
+            ScriptInterceptor::from_source("test", "fn on_packet(", SessionHandle::new()).is_err()
+        );
+    }
+}
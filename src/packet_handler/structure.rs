@@ -0,0 +1,267 @@
+use crate::packet_handler::packet::Packet;
+use crate::packet_handler::packet_error::PacketError;
+use crate::packet_handler::packet_value::PacketValue;
+use std::fmt;
+
+// A structure signature spells out a packet body one character per field:
+//
+//   i  int      u  short    l  long
+//   s  string   (S works too)
+//   B  bool     b  byte
+//   {..}  an int count followed by that many copies of whatever is inside the braces
+//
+// so "iSBi" is int, string, bool, int and "i{s}b" is an int, a counted list of strings and a byte.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Field {
+    Int,
+    Short,
+    Long,
+    Byte,
+    Bool,
+    String,
+    Group(Vec<Field>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StructureError {
+    UnknownField { found: char, position: usize },
+    UnbalancedBraces,
+    EmptyGroup,
+}
+
+impl fmt::Display for StructureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StructureError::UnknownField { found, position } => {
+                write!(f, "unknown field '{}' at position {}", found, position)
+            }
+            StructureError::UnbalancedBraces => write!(f, "unbalanced braces"),
+            StructureError::EmptyGroup => write!(f, "repeated groups can't be empty"),
+        }
+    }
+}
+
+impl std::error::Error for StructureError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Structure {
+    pub signature: String,
+    pub fields: Vec<Field>,
+}
+
+impl Structure {
+    pub fn parse(signature: &str) -> Result<Structure, StructureError> {
+        let chars = signature.chars().collect::<Vec<_>>();
+        let mut position = 0;
+        let fields = Self::parse_fields(&chars, &mut position)?;
+        if position != chars.len() {
+            return Err(StructureError::UnbalancedBraces);
+        }
+
+        Ok(Structure {
+            signature: signature.to_owned(),
+            fields,
+        })
+    }
+
+    fn parse_fields(chars: &[char], position: &mut usize) -> Result<Vec<Field>, StructureError> {
+        let mut fields = Vec::new();
+
+        while let Some(c) = chars.get(*position) {
+            let field = match c {
+                'i' => Field::Int,
+                'u' => Field::Short,
+                'l' => Field::Long,
+                'b' => Field::Byte,
+                'B' => Field::Bool,
+                's' | 'S' => Field::String,
+                '{' => {
+                    *position += 1;
+                    let group = Self::parse_fields(chars, position)?;
+                    if chars.get(*position) != Some(&'}') {
+                        return Err(StructureError::UnbalancedBraces);
+                    }
+                    if group.is_empty() {
+                        return Err(StructureError::EmptyGroup);
+                    }
+                    Field::Group(group)
+                }
+                '}' => break,
+                c if c.is_whitespace() => {
+                    *position += 1;
+                    continue;
+                }
+                _ => {
+                    return Err(StructureError::UnknownField {
+                        found: *c,
+                        position: *position,
+                    })
+                }
+            };
+            fields.push(field);
+            *position += 1;
+        }
+
+        Ok(fields)
+    }
+
+    // Reads the body from the start. Values come out flat in wire order, a group's count included,
+    // and anything the signature doesn't cover is kept as trailing bytes so the values still
+    // add up to the exact body.
+    pub fn decode(&self, packet: &mut Packet) -> Result<Vec<PacketValue>, PacketError> {
+        Ok(self.decode_with_counts(packet)?.0)
+    }
+
+    // Same as `decode`, along with the positions of the values that are group counts.
+    pub fn decode_with_counts(
+        &self,
+        packet: &mut Packet,
+    ) -> Result<(Vec<PacketValue>, Vec<usize>), PacketError> {
+        packet.reset();
+        let mut values = Vec::new();
+        let mut counts = Vec::new();
+        Self::decode_fields(&self.fields, packet, &mut values, &mut counts)?;
+
+        if packet.remaining() > 0 {
+            values.push(PacketValue::Bytes(packet.read_bytes(packet.remaining())?));
+        }
+        Ok((values, counts))
+    }
+
+    // True when the signature accounts for every byte of the body, nothing more and nothing less.
+    pub fn fits(&self, packet: &mut Packet) -> bool {
+        packet.reset();
+        Self::decode_fields(&self.fields, packet, &mut Vec::new(), &mut Vec::new()).is_ok()
+            && packet.remaining() == 0
+    }
+
+    fn decode_fields(
+        fields: &[Field],
+        packet: &mut Packet,
+        values: &mut Vec<PacketValue>,
+        counts: &mut Vec<usize>,
+    ) -> Result<(), PacketError> {
+        for field in fields {
+            let value = match field {
+                Field::Int => PacketValue::Int(packet.read_int()?),
+                Field::Short => PacketValue::Short(packet.read_short()? as u16),
+                Field::Long => PacketValue::Long(packet.read_long()?),
+                Field::Byte => PacketValue::Byte(packet.read_byte()?),
+                Field::Bool => PacketValue::Bool(packet.read_bool()?),
+                Field::String => PacketValue::String(packet.read_string()?),
+                Field::Group(group) => {
+                    let count = packet.read_int()?;
+                    if count < 0 {
+                        return Err(PacketError::InvalidCount(count));
+                    }
+                    counts.push(values.len());
+                    values.push(PacketValue::Int(count));
+                    for _ in 0..count {
+                        Self::decode_fields(group, packet, values, counts)?;
+                    }
+                    continue;
+                }
+            };
+            values.push(value);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet_handler::direction::Direction;
+    use crate::packet_handler::packet_builder::PacketBuilder;
+
+    #[test]
+    fn parses_flat_and_grouped_signatures() {
+        assert_eq!(
+            Structure::parse("iSBi").unwrap().fields,
+            vec![Field::Int, Field::String, Field::Bool, Field::Int]
+        );
+        assert_eq!(
+            Structure::parse("i{s}b").unwrap().fields,
+            vec![Field::Int, Field::Group(vec![Field::String]), Field::Byte]
+        );
+        assert_eq!(
+            Structure::parse("i{sx}"),
+            Err(StructureError::UnknownField {
+                found: 'x',
+                position: 3
+            })
+        );
+        assert_eq!(
+            Structure::parse("i{s"),
+            Err(StructureError::UnbalancedBraces)
+        );
+        assert_eq!(
+            Structure::parse("i}"),
+            Err(StructureError::UnbalancedBraces)
+        );
+        assert_eq!(Structure::parse("{}"), Err(StructureError::EmptyGroup));
+    }
+
+    #[test]
+    fn decodes_counted_groups() {
+        let mut packet = PacketBuilder::new(1, Direction::In)
+            .append_int(7)
+            .append_int(2)
+            .append_string("a")
+            .append_int(1)
+            .append_string("b")
+            .append_int(2)
+            .append_byte(9)
+            .build();
+        let structure = Structure::parse("i{si}b").unwrap();
+
+        assert_eq!(
+            structure.decode(&mut packet).unwrap(),
+            vec![
+                PacketValue::Int(7),
+                PacketValue::Int(2),
+                PacketValue::String("a".to_owned()),
+                PacketValue::Int(1),
+                PacketValue::String("b".to_owned()),
+                PacketValue::Int(2),
+                PacketValue::Byte(9),
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_bytes_the_signature_does_not_cover() {
+        let mut packet = PacketBuilder::new(1, Direction::In)
+            .append_bool(true)
+            .append_short(5)
+            .append_long(-1)
+            .append_bytes(&[1, 2])
+            .build();
+
+        assert_eq!(
+            Structure::parse("Bul")
+                .unwrap()
+                .decode(&mut packet)
+                .unwrap(),
+            vec![
+                PacketValue::Bool(true),
+                PacketValue::Short(5),
+                PacketValue::Long(-1),
+                PacketValue::Bytes(vec![1, 2]),
+            ]
+        );
+    }
+
+    #[test]
+    fn wrong_signature_is_an_error() {
+        let mut packet = PacketBuilder::new(1, Direction::In).append_int(-4).build();
+
+        assert_eq!(
+            Structure::parse("{i}").unwrap().decode(&mut packet),
+            Err(PacketError::InvalidCount(-4))
+        );
+        assert!(Structure::parse("il").unwrap().decode(&mut packet).is_err());
+    }
+}
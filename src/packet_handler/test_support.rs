@@ -0,0 +1,21 @@
+use crate::packet_handler::packet::Packet;
+use crate::packet_handler::packet_builder::PacketBuilder;
+use crate::packet_handler::packet_handler::PacketHandler;
+
+// A frame for a message with a known structure, the way interceptors get them. The structure table
+// is global and shared by every test, so a name that's already taken has to be taken with the same
+// signature, otherwise one of the tests would be decoding against the other's structure.
+pub fn message(name: &str, signature: &str, builder: PacketBuilder) -> Packet {
+    let mut packet = builder.build();
+    packet.name = Some(name.to_owned());
+
+    match PacketHandler::get_structure(&packet) {
+        Some(existing) => assert_eq!(
+            existing.signature, signature,
+            "{} is already registered with another structure",
+            name
+        ),
+        None => PacketHandler::register_structure(name, signature).unwrap(),
+    }
+    packet
+}
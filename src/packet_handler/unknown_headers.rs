@@ -0,0 +1,154 @@
+use crate::packet_handler::direction::Direction;
+use crate::packet_handler::expression;
+use crate::packet_handler::packet::{Packet, BODY_OFFSET};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// enough to guess a layout from without keeping every chat line
+const MAX_SAMPLES: usize = 3;
+const MAX_SAMPLE_SIZE: usize = 256;
+
+lazy_static::lazy_static! {
+    static ref INVENTORY: Mutex<UnknownHeaders> = Mutex::new(UnknownHeaders::new());
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownHeader {
+    pub count: u64,
+    // unix seconds
+    pub first_seen: u64,
+    pub last_seen: u64,
+    // the first few bodies, cut to MAX_SAMPLE_SIZE
+    pub samples: Vec<Vec<u8>>,
+}
+
+// Every header the definitions had no name for, so new messages can be mapped after an update.
+#[derive(Debug, Clone, Default)]
+pub struct UnknownHeaders {
+    headers: BTreeMap<(Direction, u16), UnknownHeader>,
+}
+
+impl UnknownHeaders {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, packet: &Packet, now: u64) {
+        let Ok(header) = packet.get_header() else {
+            return;
+        };
+        let body = packet.bytes.get(BODY_OFFSET..).unwrap_or_default();
+
+        let entry = self
+            .headers
+            .entry((packet.direction, header))
+            .or_insert(UnknownHeader {
+                count: 0,
+                first_seen: now,
+                last_seen: now,
+                samples: Vec::new(),
+            });
+        entry.count += 1;
+        entry.last_seen = now;
+        if entry.samples.len() < MAX_SAMPLES {
+            entry
+                .samples
+                .push(body[..body.len().min(MAX_SAMPLE_SIZE)].to_vec());
+        }
+    }
+
+    pub fn get(&self, direction: Direction, header: u16) -> Option<&UnknownHeader> {
+        self.headers.get(&(direction, header))
+    }
+
+    pub fn len(&self) -> usize {
+        self.headers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.headers.is_empty()
+    }
+
+    //   {"incoming": {"1234": {"count": 3, "first_seen": ..., "last_seen": ..., "samples": ["[0][0][0][1]"]}}, "outgoing": {...}}
+    pub fn to_json(&self) -> Value {
+        let mut incoming = serde_json::Map::new();
+        let mut outgoing = serde_json::Map::new();
+
+        for ((direction, header), entry) in &self.headers {
+            let samples: Vec<String> = entry
+                .samples
+                .iter()
+                .map(|sample| expression::escape(sample))
+                .collect();
+            let target = match direction {
+                Direction::In => &mut incoming,
+                Direction::Out => &mut outgoing,
+            };
+            target.insert(
+                header.to_string(),
+                json!({
+                    "count": entry.count,
+                    "first_seen": entry.first_seen,
+                    "last_seen": entry.last_seen,
+                    "samples": samples,
+                }),
+            );
+        }
+
+        json!({"incoming": incoming, "outgoing": outgoing})
+    }
+
+    pub fn write(&self, path: &Path) -> std::io::Result<()> {
+        let contents = serde_json::to_string_pretty(&self.to_json())?;
+        std::fs::write(path, contents)
+    }
+
+    // The inventory the proxy fills in as it goes.
+    pub fn record_seen(packet: &Packet) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or_default();
+        INVENTORY.lock().unwrap().record(packet, now);
+    }
+
+    pub fn snapshot() -> UnknownHeaders {
+        INVENTORY.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet_handler::packet_builder::PacketBuilder;
+
+    fn packet(direction: Direction, header: u16, value: i32) -> Packet {
+        PacketBuilder::new(header, direction)
+            .append_int(value)
+            .build()
+    }
+
+    #[test]
+    fn counts_per_direction_and_keeps_a_few_samples() {
+        let mut inventory = UnknownHeaders::new();
+        for second in 0..5 {
+            inventory.record(&packet(Direction::In, 77, second as i32), 100 + second);
+        }
+        inventory.record(&packet(Direction::Out, 77, 9), 200);
+
+        let incoming = inventory.get(Direction::In, 77).unwrap();
+        assert_eq!(incoming.count, 5);
+        assert_eq!(incoming.first_seen, 100);
+        assert_eq!(incoming.last_seen, 104);
+        assert_eq!(incoming.samples.len(), MAX_SAMPLES);
+        assert_eq!(incoming.samples[1], vec![0, 0, 0, 1]);
+        assert_eq!(inventory.get(Direction::Out, 77).unwrap().count, 1);
+
+        let json = inventory.to_json();
+        assert_eq!(json["incoming"]["77"]["count"], 5);
+        assert_eq!(json["outgoing"]["77"]["samples"][0], "[0][0][0][9]");
+    }
+}
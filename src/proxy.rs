@@ -2,10 +2,16 @@ use crate::{
     connection::{Connection, ConnectionState},
     hosts,
     logger::ConsoleLogger,
+    packet_handler::direction::Direction,
+    packet_handler::interceptor::InterceptorChain,
+    packet_handler::packet::Packet,
     packet_handler::packet_handler::PacketHandler,
+    packet_handler::release::ReleaseGate,
+    settings::Settings,
 };
 use std::sync::Arc;
 use tokio::io::{AsyncReadExt, BufReader};
+use tokio::sync::mpsc::UnboundedReceiver;
 use tokio::sync::Mutex;
 #[derive(Debug)]
 pub struct Proxy<'a> {
@@ -34,12 +40,38 @@ impl Proxy<'_> {
             .unwrap()
             .into_split();
 
+        let settings = self.connection.settings.clone();
+        let client_settings = settings.clone();
+        // without detection there's nothing to wait for
+        let release_gate = ReleaseGate::new(!settings.detect_release);
+        let client_release_gate = release_gate.clone();
+        let interceptors = self.connection.interceptors.clone();
+        let client_interceptors = interceptors.clone();
+        let injections = self.connection.session.attach();
         let forward_buffers_client_to_server = tokio::spawn(async move {
-            Self::forward_buffers(client_socket.0, server_socket.1, "Out").await;
+            Self::forward_buffers(
+                client_socket.0,
+                server_socket.1,
+                Direction::Out,
+                client_settings,
+                client_release_gate,
+                client_interceptors,
+                injections.to_server,
+            )
+            .await;
         });
 
         let forward_buffers_server_to_client = tokio::spawn(async move {
-            Self::forward_buffers(server_socket.0, client_socket.1, "In").await;
+            Self::forward_buffers(
+                server_socket.0,
+                client_socket.1,
+                Direction::In,
+                settings,
+                release_gate,
+                interceptors,
+                injections.to_client,
+            )
+            .await;
         });
 
         let (res1, res2) = tokio::join!(
@@ -78,7 +110,7 @@ impl Proxy<'_> {
         };
         ConsoleLogger::success(format!("Caught client connection from {}", client_address));
         client_stream.set_nodelay(true).unwrap();
-        return Ok(client_stream);
+        Ok(client_stream)
     }
 
     pub async fn wait_for_server_connection(
@@ -96,30 +128,51 @@ impl Proxy<'_> {
             server_stream.peer_addr().unwrap()
         ));
         server_stream.set_nodelay(true).unwrap();
-        return Ok(server_stream);
+        Ok(server_stream)
     }
 
     pub async fn forward_buffers(
         source_stream: tokio::net::tcp::OwnedReadHalf,
         destination_stream: tokio::net::tcp::OwnedWriteHalf,
-        direction: &'static str,
+        direction: Direction,
+        settings: Settings,
+        release_gate: ReleaseGate,
+        interceptors: InterceptorChain,
+        mut injections: UnboundedReceiver<Packet>,
     ) {
         let mut buffer = [0u8; 10000];
         let mut source_reader = BufReader::new(source_stream);
 
         let destination_stream_arc = Arc::new(Mutex::new(destination_stream));
 
-        let mut packet_handler = PacketHandler::new(&destination_stream_arc, &direction);
-        loop {
+        let mut packet_handler = PacketHandler::new(
+            &destination_stream_arc,
+            direction,
+            &settings,
+            release_gate,
+            interceptors,
+        );
+        while !packet_handler.is_closed() {
             //buffer.fill(0);
-            let read_length = match source_reader.read(&mut buffer).await {
-                Ok(n) if n != 0 => n,
-                Ok(_) | Err(_) => {
-                    continue;
+            tokio::select! {
+                read = source_reader.read(&mut buffer) => {
+                    let read_length = match read {
+                        Ok(n) if n != 0 => n,
+                        Ok(_) => {
+                            ConsoleLogger::info(format!("{} stream closed", direction));
+                            break;
+                        }
+                        Err(error) => {
+                            ConsoleLogger::error(format!("Failed to read {} stream: {}", direction, error));
+                            break;
+                        }
+                    };
+                    packet_handler.forward(&buffer[0..read_length]).await;
                 }
-            };
-
-            packet_handler.forward(&buffer[0..read_length]).await;
+                Some(packet) = injections.recv() => packet_handler.inject(packet).await,
+            }
         }
+        // closing our end of the channel is what tells the session it's over
+        injections.close();
     }
 }
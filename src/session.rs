@@ -0,0 +1,133 @@
+use crate::packet_handler::direction::Direction;
+use crate::packet_handler::packet::Packet;
+use crate::packet_handler::packet_error::PacketError;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InjectError {
+    // no client connected yet, or the session is over
+    NotConnected,
+    Malformed(PacketError),
+}
+
+impl fmt::Display for InjectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InjectError::NotConnected => write!(f, "no session to send to"),
+            InjectError::Malformed(e) => write!(f, "not a valid frame: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for InjectError {}
+
+// The receiving ends, one per direction, handed to whoever writes that direction.
+pub struct Injections {
+    pub to_client: UnboundedReceiver<Packet>,
+    pub to_server: UnboundedReceiver<Packet>,
+}
+
+#[derive(Debug)]
+struct Senders {
+    to_client: UnboundedSender<Packet>,
+    to_server: UnboundedSender<Packet>,
+}
+
+// Sends packets into the live session from anywhere. Packets don't go out directly, they're queued
+// for the task forwarding that direction, which writes them between whole frames.
+#[derive(Debug, Clone, Default)]
+pub struct SessionHandle {
+    senders: Arc<Mutex<Option<Senders>>>,
+}
+
+impl SessionHandle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Called by the proxy once both ends are connected. Anything still queued for an old session is dropped.
+    pub fn attach(&self) -> Injections {
+        let (to_client, to_client_receiver) = mpsc::unbounded_channel();
+        let (to_server, to_server_receiver) = mpsc::unbounded_channel();
+        *self.senders.lock().unwrap() = Some(Senders {
+            to_client,
+            to_server,
+        });
+        Injections {
+            to_client: to_client_receiver,
+            to_server: to_server_receiver,
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.senders
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|senders| !senders.to_client.is_closed() && !senders.to_server.is_closed())
+    }
+
+    // Was attached once but one of the forward tasks has since stopped.
+    pub fn has_ended(&self) -> bool {
+        self.senders.lock().unwrap().is_some() && !self.is_connected()
+    }
+
+    // In packets go to the client, Out packets to the server.
+    pub fn send(&self, packet: Packet) -> Result<(), InjectError> {
+        let declared = packet.read_length().map_err(InjectError::Malformed)?;
+        if declared < 2 || declared as usize != packet.bytes.len() - 4 {
+            return Err(InjectError::Malformed(PacketError::InvalidLength {
+                declared,
+                actual: packet.bytes.len() - 4,
+            }));
+        }
+
+        let senders = self.senders.lock().unwrap();
+        let senders = senders.as_ref().ok_or(InjectError::NotConnected)?;
+        let sender = match packet.direction {
+            Direction::In => &senders.to_client,
+            Direction::Out => &senders.to_server,
+        };
+        sender.send(packet).map_err(|_| InjectError::NotConnected)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet_handler::packet_builder::PacketBuilder;
+
+    #[test]
+    fn routes_by_direction_once_attached() {
+        let session = SessionHandle::new();
+        let to_server = PacketBuilder::new(1, Direction::Out).build();
+        assert_eq!(
+            session.send(to_server.clone()),
+            Err(InjectError::NotConnected)
+        );
+
+        let mut injections = session.attach();
+        session.clone().send(to_server.clone()).unwrap();
+        session
+            .send(PacketBuilder::new(2, Direction::In).build())
+            .unwrap();
+
+        assert_eq!(injections.to_server.try_recv().unwrap(), to_server);
+        assert_eq!(injections.to_client.try_recv().unwrap().get_header(), Ok(2));
+    }
+
+    #[test]
+    fn rejects_frames_with_a_wrong_length() {
+        let session = SessionHandle::new();
+        let _injections = session.attach();
+        let mut packet = PacketBuilder::new(1, Direction::Out).build();
+        packet.bytes.push(0);
+
+        assert!(matches!(
+            session.send(packet),
+            Err(InjectError::Malformed(_))
+        ));
+    }
+}
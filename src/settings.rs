@@ -0,0 +1,201 @@
+use crate::logger::{ConsoleLogger, LogFilter};
+use crate::packet_handler::plugin::PluginLimits;
+use crate::packet_handler::reassembler::FrameLimits;
+use crate::packet_handler::release::DEFAULT_RELEASE;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::time::Duration;
+
+pub const SETTINGS_FILE: &str = "hablog.json";
+
+// Where message definitions come from, see `definitions::sources`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SourceConfig {
+    Sulek,
+    File(String),
+    GEarth(String),
+}
+
+// Everything here is optional in hablog.json, anything missing keeps its default.
+#[derive(Debug, Clone)]
+pub struct Settings {
+    pub frame_limits: FrameLimits,
+    // message name -> structure signature, e.g. "Chat": "sii"
+    pub structures: HashMap<String, String>,
+    pub log_filter: LogFilter,
+    // sulek.dev style JSON to use instead of asking the API
+    pub messages_file: Option<String>,
+    // in order of precedence, highest first
+    pub sources: Vec<SourceConfig>,
+    pub cache_dir: String,
+    // local renames, aliases, structures and hidden messages, applied on top of every source
+    pub overrides_file: String,
+    // where the unknown-header inventory is dumped
+    pub unknown_headers_file: String,
+    // drop/pass rules, reloaded whenever the file changes
+    pub rules_file: String,
+    // *.rhai packet handlers, loaded at startup
+    pub scripts_dir: String,
+    // *.wasm packet plugins and what each one may use
+    pub plugins_dir: String,
+    pub plugin_limits: PluginLimits,
+    // cached definitions older than this are thrown away instead of used
+    pub cache_max_age: Duration,
+    // client release to load definitions for until the handshake tells us otherwise
+    pub release: String,
+    pub detect_release: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            frame_limits: FrameLimits::default(),
+            structures: HashMap::new(),
+            log_filter: LogFilter::Both,
+            messages_file: None,
+            sources: vec![SourceConfig::Sulek],
+            cache_dir: String::from("cache"),
+            overrides_file: String::from("overrides.json"),
+            unknown_headers_file: String::from("unknown-headers.json"),
+            rules_file: String::from("rules.json"),
+            scripts_dir: String::from("scripts"),
+            plugins_dir: String::from("plugins"),
+            plugin_limits: PluginLimits::default(),
+            cache_max_age: Duration::from_secs(30 * 24 * 60 * 60),
+            release: String::from(DEFAULT_RELEASE),
+            detect_release: true,
+        }
+    }
+}
+
+impl Settings {
+    pub fn load(path: &str) -> Settings {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(_) => return Settings::default(),
+        };
+
+        match serde_json::from_str::<Value>(&contents) {
+            Ok(json) => {
+                ConsoleLogger::normal(format!("Loaded settings from {}", path));
+                Settings::from_json(&json)
+            }
+            Err(e) => {
+                ConsoleLogger::warning(format!("Ignoring {}: {}", path, e));
+                Settings::default()
+            }
+        }
+    }
+
+    pub fn from_json(json: &Value) -> Settings {
+        let mut settings = Settings::default();
+
+        if let Some(max_frame_size) = json.get("max_frame_size").and_then(|v| v.as_u64()) {
+            settings.frame_limits.max_frame_size = max_frame_size as usize;
+        }
+        if let Some(max_header) = json.get("max_header").and_then(|v| v.as_u64()) {
+            settings.frame_limits.max_header = max_header.min(u16::MAX as u64) as u16;
+        }
+
+        if let Some(log) = json.get("log").and_then(|v| v.as_str()) {
+            match LogFilter::parse(log) {
+                Some(filter) => settings.log_filter = filter,
+                None => ConsoleLogger::warning(format!(
+                    "Unknown log setting \"{}\", expected in, out or both",
+                    log
+                )),
+            }
+        }
+        if let Some(messages_file) = json.get("messages_file").and_then(|v| v.as_str()) {
+            settings.messages_file = Some(messages_file.to_owned());
+            // a local file replaces the API unless sources says otherwise
+            settings.sources.clear();
+        }
+        if let Some(sources) = json.get("sources").and_then(|v| v.as_array()) {
+            settings.sources = sources
+                .iter()
+                .filter_map(|source| {
+                    let parsed = Self::source_from_json(source);
+                    if parsed.is_none() {
+                        ConsoleLogger::warning(format!("Ignoring unknown source {}", source));
+                    }
+                    parsed
+                })
+                .collect();
+        }
+        if let Some(overrides_file) = json.get("overrides_file").and_then(|v| v.as_str()) {
+            settings.overrides_file = overrides_file.to_owned();
+        }
+        if let Some(unknown_headers_file) =
+            json.get("unknown_headers_file").and_then(|v| v.as_str())
+        {
+            settings.unknown_headers_file = unknown_headers_file.to_owned();
+        }
+        if let Some(rules_file) = json.get("rules_file").and_then(|v| v.as_str()) {
+            settings.rules_file = rules_file.to_owned();
+        }
+        if let Some(scripts_dir) = json.get("scripts_dir").and_then(|v| v.as_str()) {
+            settings.scripts_dir = scripts_dir.to_owned();
+        }
+        if let Some(plugins_dir) = json.get("plugins_dir").and_then(|v| v.as_str()) {
+            settings.plugins_dir = plugins_dir.to_owned();
+        }
+        if let Some(fuel) = json.get("plugin_fuel").and_then(|v| v.as_u64()) {
+            settings.plugin_limits.fuel = fuel;
+        }
+        if let Some(megabytes) = json.get("plugin_max_memory_mb").and_then(|v| v.as_u64()) {
+            match usize::try_from(megabytes)
+                .ok()
+                .and_then(|megabytes| megabytes.checked_mul(1024 * 1024))
+            {
+                Some(bytes) => settings.plugin_limits.max_memory = bytes,
+                None => ConsoleLogger::warning(format!(
+                    "plugin_max_memory_mb {} is too large, keeping the default",
+                    megabytes
+                )),
+            }
+        }
+        if let Some(cache_dir) = json.get("cache_dir").and_then(|v| v.as_str()) {
+            settings.cache_dir = cache_dir.to_owned();
+        }
+        if let Some(hours) = json.get("cache_max_age_hours").and_then(|v| v.as_u64()) {
+            match hours.checked_mul(60 * 60) {
+                Some(seconds) => settings.cache_max_age = Duration::from_secs(seconds),
+                None => ConsoleLogger::warning(format!(
+                    "cache_max_age_hours {} is too large, keeping the default",
+                    hours
+                )),
+            }
+        }
+        if let Some(release) = json.get("release").and_then(|v| v.as_str()) {
+            settings.release = release.to_owned();
+        }
+        if let Some(detect_release) = json.get("detect_release").and_then(|v| v.as_bool()) {
+            settings.detect_release = detect_release;
+        }
+        if let Some(structures) = json.get("structures").and_then(|v| v.as_object()) {
+            settings.structures = structures
+                .iter()
+                .filter_map(|(name, signature)| {
+                    Some((name.clone(), signature.as_str()?.to_owned()))
+                })
+                .collect();
+        }
+
+        settings
+    }
+
+    // {"type": "sulek"}, {"type": "file", "path": "..."} or {"type": "gearth", "path": "..."}
+    fn source_from_json(source: &Value) -> Option<SourceConfig> {
+        let path = source
+            .get("path")
+            .and_then(|v| v.as_str())
+            .map(str::to_owned);
+        match source.get("type").and_then(|v| v.as_str())? {
+            "sulek" => Some(SourceConfig::Sulek),
+            "file" => Some(SourceConfig::File(path?)),
+            "gearth" => Some(SourceConfig::GEarth(path?)),
+            _ => None,
+        }
+    }
+}